@@ -0,0 +1,935 @@
+// Catalog of the addons hake can install into a cluster, with the pinned
+// manifests of every version. The catalog lives in addons.yaml.
+//
+// `hake addons fetch` keeps the manifests, and optionally the images, of
+// an addon version under ~/.hake/cache/addons/<name>/<version>, so they
+// can be installed without network access. The checksums of the fetched
+// manifests are kept next to them, and checked whenever they are read.
+//
+// The manifests applied to a cluster are kept in its directory, under
+// addons/<name>, so `hake remove` deletes exactly what was installed.
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::cmd;
+use crate::list;
+use crate::ready;
+use crate::state::{self, ClusterState, InstalledAddon};
+use crate::wait::{self, Progress};
+
+const CATALOG: &str = include_str!("addons.yaml");
+
+/// Directory in ~/.hake holding downloaded files, not a cluster.
+pub const CACHE_DIR: &str = "cache";
+const IMAGES_ARCHIVE: &str = "images.tar";
+/// Checksums of the cached manifests, in the `sha256sum` format.
+const SUMS_FILE: &str = "sha256sums";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub url: String,
+    /// Hex encoded sha256 of the manifest, checked when given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddonVersion {
+    pub version: String,
+    pub manifests: Vec<Manifest>,
+    /// Images used by the manifests, side-loaded into kind clusters when
+    /// installing offline. Images named in the manifests are side-loaded
+    /// too, see `images`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+/// Something that must be ready before an addon can be used, like
+/// `deployment: cert-manager/cert-manager-webhook` in the catalog.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    /// Deployment `namespace/name` has all of its replicas available.
+    Deployment(String),
+    /// Custom resource definition `name` is established.
+    Crd(String),
+    /// The webhooks of the validating and mutating configurations with
+    /// this name have a CA bundle and a ready endpoint.
+    Webhook(String),
+    /// This object can be created with a server side dry run, which calls
+    /// the admission webhooks for real.
+    Probe(String),
+}
+
+impl Check {
+    /// What is still missing, nothing when the check passes.
+    fn pending(&self, kubeconfig: &str) -> Vec<String> {
+        match self {
+            Check::Deployment(name) => ready::deployment(kubeconfig, name),
+            Check::Crd(name) => ready::crd(kubeconfig, name),
+            Check::Webhook(name) => ready::webhook(kubeconfig, name),
+            Check::Probe(manifest) => ready::probe(kubeconfig, manifest),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Addon {
+    pub name: String,
+    pub description: String,
+    /// Version installed when none is asked for.
+    pub default: String,
+    /// Newest first.
+    pub versions: Vec<AddonVersion>,
+    /// Checked after installing, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ready: Vec<Check>,
+    /// Providers whose clusters the manifests are made for, any if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub providers: Vec<String>,
+}
+
+/// How `hake add` installs an addon.
+#[derive(Debug, Default)]
+pub struct AddOptions {
+    /// Version like `1.12`, the default version if not set.
+    pub version: Option<String>,
+    /// Only use the cache, see `fetch`.
+    pub offline: bool,
+    /// How long to wait for the addon to be ready, 5 minutes by default.
+    pub timeout: Option<Duration>,
+    pub no_wait: bool,
+}
+
+impl Addon {
+    /// Fails if the manifests are not made for clusters of `provider`.
+    pub fn check_provider(&self, provider: &str) -> Result<()> {
+        if self.providers.is_empty() || self.providers.iter().any(|p| p == provider) {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "{} can only be installed into {} clusters",
+            self.name,
+            self.providers.join(", ")
+        ))
+    }
+
+    /// The version matching `requested`, like `1.12` or `1.12.3`, or the
+    /// default one. Partial versions pick the newest match.
+    pub fn version(&self, requested: Option<&str>) -> Result<&AddonVersion> {
+        let requested = requested
+            .map(|v| v.trim_start_matches('v'))
+            .unwrap_or(&self.default);
+
+        self.versions
+            .iter()
+            .find(|v| v.version == requested || v.version.starts_with(&format!("{}.", requested)))
+            .ok_or_else(|| {
+                let known: Vec<&str> = self.versions.iter().map(|v| &v.version[..]).collect();
+                anyhow!(
+                    "Unknown version {} of {}. Known versions are: {}",
+                    requested,
+                    self.name,
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+/// All the addons hake knows about.
+pub fn catalog() -> Vec<Addon> {
+    serde_yaml::from_str(CATALOG).expect("addons.yaml is not a valid catalog")
+}
+
+/// Names of the known addons.
+pub fn names() -> Vec<String> {
+    catalog().into_iter().map(|a| a.name).collect()
+}
+
+pub fn find(name: &str) -> Result<Addon> {
+    catalog()
+        .into_iter()
+        .find(|a| a.name == name)
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown addon: {}. Known addons are: {}",
+                name,
+                names().join(", ")
+            )
+        })
+}
+
+pub(crate) fn sha256(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Fails if `contents` of `what` don't match the `expected` checksum.
+fn check_sum(what: &str, expected: &str, contents: &[u8]) -> Result<()> {
+    let actual = sha256(contents);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!(
+            "Checksum of {} does not match: expected {}, got {}",
+            what,
+            expected,
+            actual
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fails if `contents` don't match the checksum of `manifest`.
+fn verify(manifest: &Manifest, contents: &[u8]) -> Result<()> {
+    match &manifest.sha256 {
+        Some(expected) => check_sum(&manifest.url, expected, contents),
+        None => {
+            log::warn!(
+                "{} has no sha256 in the catalog, it is not checked",
+                manifest.url
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Downloads `manifest` and checks it.
+fn download(manifest: &Manifest) -> Result<String> {
+    let mut resp = reqwest::blocking::get(&manifest.url)
+        .with_context(|| format!("Could not download {}", manifest.url))?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Could not download {}. Status code is: {}",
+            manifest.url,
+            resp.status()
+        ));
+    }
+
+    let mut contents = vec![];
+    resp.copy_to(&mut contents)?;
+    verify(manifest, &contents)?;
+
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Directory caching `version` of `addon`.
+pub fn cache_dir(addon: &Addon, version: &AddonVersion) -> Result<String> {
+    Ok(format!(
+        "{}/{}/addons/{}/{}",
+        crate::get_config_dir()?,
+        CACHE_DIR,
+        addon.name,
+        version.version
+    ))
+}
+
+/// Name of the cached copy of the `index`-th manifest, keeping them in
+/// the order they are applied.
+fn cached_manifest(dir: &str, index: usize, manifest: &Manifest) -> String {
+    let file = manifest.url.rsplit('/').next().unwrap_or("manifest.yaml");
+
+    format!("{}/{}-{}", dir, index, file)
+}
+
+/// Checksums recorded by `fetch` in `dir`, by file name.
+fn recorded_sums(dir: &str) -> Result<BTreeMap<String, String>> {
+    let path = format!("{}/{}", dir, SUMS_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path)),
+    };
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(sum, file)| (String::from(file), String::from(sum)))
+        .collect())
+}
+
+/// Images named in `manifest`, like `registry.k8s.io/ingress-nginx/controller:v1.8.1@sha256:...`.
+fn manifest_images(manifest: &str) -> Vec<String> {
+    let re = Regex::new(r#"(?m)^\s*(?:-\s+)?image:\s*["']?([^"'\s]+)"#).unwrap();
+
+    re.captures_iter(manifest)
+        .map(|c| String::from(&c[1]))
+        .collect()
+}
+
+/// The images of `version`, along with the ones named in its `manifests`.
+fn images(version: &AddonVersion, manifests: &[String]) -> Vec<String> {
+    let mut images = version.images.clone();
+    for image in manifests.iter().flat_map(|m| manifest_images(m)) {
+        if !images.contains(&image) {
+            images.push(image);
+        }
+    }
+
+    images
+}
+
+/// `image` without its digest, like `controller:v1.8.1` for
+/// `controller:v1.8.1@sha256:...`. Images without a tag keep their digest.
+fn unpinned(image: &str) -> &str {
+    match image.split_once('@') {
+        Some((name, _)) if name.rsplit('/').next().unwrap_or(name).contains(':') => name,
+        _ => image,
+    }
+}
+
+/// `manifest` with the images pinned by digest named by tag, as images
+/// side-loaded into kind clusters don't keep the registry digest.
+fn without_digests(manifest: &str) -> String {
+    let mut unpinned_manifest = String::from(manifest);
+    for image in manifest_images(manifest) {
+        let name = unpinned(&image);
+        if name != image {
+            unpinned_manifest = unpinned_manifest.replace(&image, name);
+        }
+    }
+
+    unpinned_manifest
+}
+
+/// Downloads the manifests of `version` of `addon` into the cache, and
+/// saves its images too if `images` is set. Images pinned by digest are
+/// pulled by digest and saved by tag.
+pub fn fetch(addon: &Addon, version: &AddonVersion, images: bool) -> Result<()> {
+    let dir = cache_dir(addon, version)?;
+    fs::create_dir_all(&dir)?;
+
+    let mut manifests = vec![];
+    let mut sums = String::new();
+    for (index, manifest) in version.manifests.iter().enumerate() {
+        log::info!("Downloading {}", manifest.url);
+        let contents = download(manifest)?;
+        let cached = cached_manifest(&dir, index, manifest);
+        fs::write(&cached, &contents)?;
+
+        let file = cached.rsplit('/').next().unwrap_or(&cached);
+        sums.push_str(&format!("{}  {}\n", sha256(contents.as_bytes()), file));
+        manifests.push(contents);
+    }
+    fs::write(format!("{}/{}", dir, SUMS_FILE), sums)?;
+
+    let all = self::images(version, &manifests);
+    if images && !all.is_empty() {
+        for image in &all {
+            log::info!("Pulling {}", image);
+            cmd::run("docker", &["pull", image])?;
+            let name = unpinned(image);
+            if name != image {
+                let digest = image.rsplit('@').next().unwrap_or_default();
+                let repository = name.rsplit_once(':').map(|(r, _)| r).unwrap_or(name);
+                cmd::run(
+                    "docker",
+                    &["tag", &format!("{}@{}", repository, digest), name],
+                )?;
+            }
+        }
+
+        let archive = format!("{}/{}", dir, IMAGES_ARCHIVE);
+        let mut args = vec!["save", "--output", &archive];
+        args.extend(all.iter().map(|i| unpinned(i)));
+        cmd::run("docker", &args)?;
+    }
+
+    Ok(())
+}
+
+/// Contents of `manifest`, read from the cache if it was fetched before.
+/// Only the cache is looked at when `offline` is set.
+fn manifest_contents(
+    addon: &Addon,
+    version: &AddonVersion,
+    index: usize,
+    offline: bool,
+) -> Result<String> {
+    let manifest = &version.manifests[index];
+    let cached = cached_manifest(&cache_dir(addon, version)?, index, manifest);
+
+    if Path::new(&cached).exists() {
+        let contents = fs::read(&cached)?;
+        let file = cached.rsplit('/').next().unwrap_or(&cached);
+        match recorded_sums(&cache_dir(addon, version)?)?.get(file) {
+            Some(expected) => check_sum(&cached, expected, &contents)?,
+            None => {
+                return Err(anyhow!(
+                    "{} has no recorded checksum. Fetch it again with: hake addons fetch {} --version {}",
+                    cached,
+                    addon.name,
+                    version.version
+                ))
+            }
+        }
+        verify(manifest, &contents)?;
+
+        return Ok(String::from_utf8_lossy(&contents).into_owned());
+    }
+    if offline {
+        return Err(anyhow!(
+            "{} {} is not in the cache. Fetch it with: hake addons fetch {} --version {}",
+            addon.name,
+            version.version,
+            addon.name,
+            version.version
+        ));
+    }
+
+    download(manifest)
+}
+
+/// Loads the cached images of `version` of `addon` into the nodes of kind
+/// cluster `cluster`, so they don't need to be pulled. Returns whether
+/// there were images to load.
+pub fn load_images(addon: &Addon, version: &AddonVersion, cluster: &str) -> Result<bool> {
+    let archive = format!("{}/{}", cache_dir(addon, version)?, IMAGES_ARCHIVE);
+    if !Path::new(&archive).exists() {
+        log::info!(
+            "Images of {} {} are not in the cache, nodes will pull them",
+            addon.name,
+            version.version
+        );
+        return Ok(false);
+    }
+
+    log::info!("Loading images of {} into: {}", addon.name, cluster);
+    cmd::run(
+        "kind",
+        &["load", "image-archive", &archive, "--name", cluster],
+    )?;
+
+    Ok(true)
+}
+
+/// Waits until the checks of `addon` pass in the cluster of `kubeconfig`.
+/// The error names the components that are not ready.
+pub fn wait(addon: &Addon, kubeconfig: &str, timeout: Option<Duration>) -> Result<()> {
+    if addon.ready.is_empty() {
+        return Ok(());
+    }
+
+    wait::until(&addon.name, timeout.unwrap_or(DEFAULT_TIMEOUT), || {
+        let pending: Vec<String> = addon
+            .ready
+            .iter()
+            .flat_map(|check| check.pending(kubeconfig))
+            .collect();
+
+        if pending.is_empty() {
+            Ok(Progress::Done)
+        } else {
+            Ok(Progress::Pending(pending.join(", ")))
+        }
+    })
+}
+
+/// Table with the known addons and their versions.
+pub fn table(addons: &[Addon]) -> String {
+    let mut rows = vec![vec![
+        String::from("NAME"),
+        String::from("DEFAULT"),
+        String::from("VERSIONS"),
+        String::from("DESCRIPTION"),
+    ]];
+    for addon in addons {
+        let versions: Vec<&str> = addon.versions.iter().map(|v| &v.version[..]).collect();
+        rows.push(vec![
+            addon.name.clone(),
+            addon.default.clone(),
+            versions.join(", "),
+            addon.description.clone(),
+        ]);
+    }
+
+    list::columns(rows)
+}
+
+/// Applies the manifests of `version` of `addon` to the cluster of
+/// `kubeconfig`, from the cache when possible, and returns what was
+/// applied. Nothing is downloaded when `offline` is set. `loaded_images`
+/// tells the images were side-loaded with `load_images`, so the manifests
+/// name them by tag.
+pub fn install(
+    addon: &Addon,
+    version: &AddonVersion,
+    kubeconfig: &str,
+    offline: bool,
+    loaded_images: bool,
+) -> Result<Vec<String>> {
+    let mut applied = vec![];
+    for index in 0..version.manifests.len() {
+        let mut contents = manifest_contents(addon, version, index, offline)?;
+        if loaded_images {
+            contents = without_digests(&contents);
+        }
+        cmd::run_with_input(
+            "kubectl",
+            &["--kubeconfig", kubeconfig, "apply", "-f", "-"],
+            &contents,
+        )
+        .with_context(|| format!("Could not install {} {}", addon.name, version.version))?;
+        applied.push(contents);
+    }
+
+    Ok(applied)
+}
+
+/// Records that `version` of `addon` was installed into the cluster of
+/// `state`, keeping the `manifests` that were applied. Installing another
+/// version replaces the previous one.
+pub fn record(
+    state: &mut ClusterState,
+    addon: &Addon,
+    version: &AddonVersion,
+    manifests: &[String],
+) -> Result<()> {
+    let dir = state.addon_dir(&addon.name);
+    if Path::new(&dir).exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    for (index, manifest) in manifests.iter().enumerate() {
+        fs::write(format!("{}/{}.yaml", dir, index), manifest)?;
+    }
+
+    state.addons.retain(|a| a.name != addon.name);
+    state.addons.push(InstalledAddon {
+        name: addon.name.clone(),
+        version: version.version.clone(),
+        installed_at: state::now(),
+    });
+
+    state.save()
+}
+
+/// The documents of a multi-document YAML `manifest` in reverse order, so
+/// objects are deleted before the namespaces and CRDs they depend on.
+fn reversed(manifest: &str) -> String {
+    let mut documents: Vec<String> = vec![];
+    let mut current = String::new();
+    for line in manifest.lines() {
+        if line.trim_end() == "---" {
+            documents.push(current);
+            current = String::new();
+        } else {
+            current.push_str(line);
+            current.push('\n');
+        }
+    }
+    documents.push(current);
+
+    let documents: Vec<String> = documents
+        .into_iter()
+        .filter(|d| {
+            d.lines()
+                .any(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        })
+        .rev()
+        .collect();
+
+    documents.join("---\n")
+}
+
+/// Deletes the manifests applied for addon `name` from the cluster of
+/// `kubeconfig`, last applied first, and forgets about the addon.
+pub fn remove(state: &mut ClusterState, name: &str, kubeconfig: &str) -> Result<()> {
+    if !state.addons.iter().any(|a| a.name == name) {
+        return Err(anyhow!(
+            "Addon {} is not installed in cluster {}",
+            name,
+            state.name
+        ));
+    }
+
+    let dir = state.addon_dir(name);
+    let mut manifests: Vec<(usize, String)> = vec![];
+    if Path::new(&dir).exists() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let index = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok());
+            if let Some(index) = index {
+                manifests.push((index, fs::read_to_string(&path)?));
+            }
+        }
+    }
+    manifests.sort_by_key(|m| std::cmp::Reverse(m.0));
+
+    for (_, manifest) in manifests {
+        cmd::run_with_input(
+            "kubectl",
+            &[
+                "--kubeconfig",
+                kubeconfig,
+                "delete",
+                "--ignore-not-found",
+                "-f",
+                "-",
+            ],
+            &reversed(&manifest),
+        )
+        .with_context(|| format!("Could not remove {}", name))?;
+    }
+
+    if Path::new(&dir).exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    state.addons.retain(|a| a.name != name);
+
+    state.save()
+}
+
+/// Table with the addons installed into a cluster.
+pub fn installed_table(addons: &[InstalledAddon]) -> String {
+    let now = state::now();
+    let mut rows = vec![vec![
+        String::from("NAME"),
+        String::from("VERSION"),
+        String::from("AGE"),
+    ]];
+    for addon in addons {
+        rows.push(vec![
+            addon.name.clone(),
+            addon.version.clone(),
+            list::age(addon.installed_at, now),
+        ]);
+    }
+
+    list::columns(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::addons::{self, Check, Manifest};
+    use crate::cmd;
+    use crate::testing::{Env, FakeRunner, MockServer};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_catalog() {
+        let hex = regex::Regex::new("^[0-9a-f]{64}$").unwrap();
+        for addon in addons::catalog() {
+            assert!(addon.version(None).is_ok(), "{} has no default", addon.name);
+            for version in &addon.versions {
+                assert!(!version.manifests.is_empty());
+                for manifest in &version.manifests {
+                    if let Some(sha256) = &manifest.sha256 {
+                        assert!(hex.is_match(sha256), "{} has a bad sha256", manifest.url);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "the sha256 of the released manifests need to be added to addons.yaml"]
+    fn test_catalog_checksums() {
+        for addon in addons::catalog() {
+            for version in &addon.versions {
+                for manifest in &version.manifests {
+                    assert!(manifest.sha256.is_some(), "{} has no sha256", manifest.url);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let addon = addons::find("cert-manager").unwrap();
+        assert_eq!(addon.version(None).unwrap().version, addon.default);
+        assert_eq!(addon.version(Some("1.11")).unwrap().version, "1.11.4");
+        assert_eq!(addon.version(Some("v1.13.1")).unwrap().version, "1.13.1");
+        assert!(addon
+            .version(Some("1.1"))
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Unknown version 1.1 of cert-manager. Known versions are: 1.13.1"));
+
+        assert_eq!(
+            addons::find("istio").err().unwrap().to_string(),
+            "Unknown addon: istio. Known addons are: cert-manager, ingress-nginx"
+        );
+    }
+
+    #[test]
+    fn test_install() {
+        let mut env = Env::new();
+        env.scratch_home("addons-install");
+        let server = MockServer::start(|_, path, _| match path {
+            "/deploy.yaml" => (200, String::from("kind: Namespace")),
+            _ => (404, String::new()),
+        });
+        let manifest = |path: &str, sha256: Option<&str>| Manifest {
+            url: format!("{}{}", server.url, path),
+            sha256: sha256.map(String::from),
+        };
+
+        let mut addon = addons::find("ingress-nginx").unwrap();
+        addon.versions[0].manifests = vec![manifest(
+            "/deploy.yaml",
+            Some(&addons::sha256(b"kind: Namespace")),
+        )];
+        let runner = Rc::new(FakeRunner::new());
+        cmd::with_runner(runner.clone(), || {
+            addons::install(&addon, &addon.versions[0], "kubeconfig", false, false)
+        })
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec!["kubectl --kubeconfig kubeconfig apply -f -"]
+        );
+
+        addon.versions[0].manifests = vec![manifest("/deploy.yaml", Some("abc"))];
+        assert!(
+            addons::install(&addon, &addon.versions[0], "kubeconfig", false, false)
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("Checksum of")
+        );
+
+        addon.versions[0].manifests = vec![manifest("/missing.yaml", None)];
+        assert!(addons::install(&addon, &addon.versions[0], "kubeconfig", false, false).is_err());
+    }
+
+    #[test]
+    fn test_fetch_offline() {
+        let mut env = Env::new();
+        let home = env.scratch_home("addons-fetch");
+        let server = MockServer::start(|_, _, _| (200, String::from("kind: Namespace")));
+
+        let mut addon = addons::find("cert-manager").unwrap();
+        addon.versions[1].manifests = vec![Manifest {
+            url: format!("{}/v1.12.3/cert-manager.yaml", server.url),
+            sha256: None,
+        }];
+        let version = &addon.versions[1];
+
+        let err = addons::install(&addon, version, "kubeconfig", true, false)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cert-manager 1.12.3 is not in the cache. Fetch it with: hake addons fetch cert-manager --version 1.12.3"
+        );
+
+        let dir = format!("{}/cache/addons/cert-manager/1.12.3", home);
+        let runner = Rc::new(FakeRunner::new());
+        cmd::with_runner(runner.clone(), || addons::fetch(&addon, version, true)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/0-cert-manager.yaml", dir)).unwrap(),
+            "kind: Namespace"
+        );
+        assert!(crate::clusters().unwrap().is_empty());
+        let calls = runner.calls();
+        assert_eq!(
+            calls[0],
+            "docker pull quay.io/jetstack/cert-manager-cainjector:v1.12.3"
+        );
+        assert_eq!(
+            calls[3],
+            format!(
+                "docker save --output {}/images.tar quay.io/jetstack/cert-manager-cainjector:v1.12.3 \
+                 quay.io/jetstack/cert-manager-controller:v1.12.3 quay.io/jetstack/cert-manager-webhook:v1.12.3",
+                dir
+            )
+        );
+
+        // docker is faked, so is the archive it saves
+        std::fs::write(format!("{}/images.tar", dir), "").unwrap();
+        let downloads = server.requests().len();
+        let runner = Rc::new(FakeRunner::new());
+        cmd::with_runner(runner.clone(), || {
+            let loaded = addons::load_images(&addon, version, "tests")?;
+            addons::install(&addon, version, "kubeconfig", true, loaded)
+        })
+        .unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!("kind load image-archive {}/images.tar --name tests", dir),
+                String::from("kubectl --kubeconfig kubeconfig apply -f -"),
+            ]
+        );
+        assert_eq!(server.requests().len(), downloads);
+
+        // the cache is checked against the checksums recorded by fetch
+        std::fs::write(format!("{}/0-cert-manager.yaml", dir), "kind: Secret").unwrap();
+        assert!(addons::install(&addon, version, "kubeconfig", true, false)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with(&format!("Checksum of {}/0-cert-manager.yaml", dir)));
+    }
+
+    const PINNED: &str = "      containers:
+        - name: controller
+          image: registry.k8s.io/ingress-nginx/controller:v1.8.1@sha256:e5c4824e
+      - image: \"registry.k8s.io/pause\"
+";
+
+    #[test]
+    fn test_manifest_images() {
+        assert_eq!(
+            addons::manifest_images(PINNED),
+            vec![
+                "registry.k8s.io/ingress-nginx/controller:v1.8.1@sha256:e5c4824e",
+                "registry.k8s.io/pause",
+            ]
+        );
+        assert_eq!(
+            addons::unpinned("registry.k8s.io/ingress-nginx/controller:v1.8.1@sha256:e5c4824e"),
+            "registry.k8s.io/ingress-nginx/controller:v1.8.1"
+        );
+        assert_eq!(
+            addons::unpinned("localhost:5000/app@sha256:e5c4824e"),
+            "localhost:5000/app@sha256:e5c4824e"
+        );
+        assert!(addons::without_digests(PINNED)
+            .contains("image: registry.k8s.io/ingress-nginx/controller:v1.8.1\n"));
+    }
+
+    #[test]
+    fn test_fetch_pinned_images() {
+        let mut env = Env::new();
+        let home = env.scratch_home("addons-pinned");
+        let server = MockServer::start(|_, _, _| (200, String::from(PINNED)));
+
+        let mut addon = addons::find("ingress-nginx").unwrap();
+        addon.versions[0].manifests = vec![Manifest {
+            url: format!("{}/deploy.yaml", server.url),
+            sha256: None,
+        }];
+        let version = &addon.versions[0];
+
+        let runner = Rc::new(FakeRunner::new());
+        cmd::with_runner(runner.clone(), || addons::fetch(&addon, version, true)).unwrap();
+        let dir = format!("{}/cache/addons/ingress-nginx/{}", home, version.version);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                String::from(
+                    "docker pull registry.k8s.io/ingress-nginx/controller:v1.8.1@sha256:e5c4824e"
+                ),
+                String::from(
+                    "docker tag registry.k8s.io/ingress-nginx/controller@sha256:e5c4824e \
+                     registry.k8s.io/ingress-nginx/controller:v1.8.1"
+                ),
+                String::from("docker pull registry.k8s.io/pause"),
+                format!(
+                    "docker save --output {}/images.tar registry.k8s.io/ingress-nginx/controller:v1.8.1 \
+                     registry.k8s.io/pause",
+                    dir
+                ),
+            ]
+        );
+
+        // the side-loaded images are used instead of pulling them by digest
+        std::fs::write(format!("{}/images.tar", dir), "").unwrap();
+        let applied = cmd::with_runner(Rc::new(FakeRunner::new()), || {
+            let loaded = addons::load_images(&addon, version, "tests")?;
+            addons::install(&addon, version, "kubeconfig", true, loaded)
+        })
+        .unwrap();
+        assert!(!applied[0].contains("@sha256"));
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut addon = addons::find("cert-manager").unwrap();
+        addon.ready = vec![
+            Check::Crd(String::from("certificates.cert-manager.io")),
+            Check::Deployment(String::from("cert-manager/cert-manager-webhook")),
+            Check::Webhook(String::from("cert-manager-webhook")),
+            Check::Probe(String::from("kind: Issuer")),
+        ];
+        let crd = r#"{"status": {"conditions": [{"type": "Established", "status": "True"}]}}"#;
+        let webhooks = r#"{"items": [{"webhooks": [{"name": "webhook.cert-manager.io",
+            "clientConfig": {"caBundle": "LS0t",
+                "service": {"namespace": "cert-manager", "name": "cert-manager-webhook"}}}]}]}"#;
+        let deployment = |available: u32| {
+            format!(
+                r#"{{"metadata": {{"name": "cert-manager-webhook"}}, "spec": {{"replicas": 1}},
+                    "status": {{"updatedReplicas": 1, "availableReplicas": {available},
+                        "conditions": [{{"type": "Available", "status": "True"}}]}}}}"#,
+                available = available
+            )
+        };
+
+        let runner = Rc::new(
+            FakeRunner::new()
+                .on("get customresourcedefinition", crd)
+                .on("get deployment", &deployment(1))
+                .on("get validatingwebhookconfigurations", webhooks)
+                .on(
+                    "get endpoints",
+                    r#"{"subsets": [{"addresses": [{"ip": "10.244.0.5"}]}]}"#,
+                ),
+        );
+        cmd::with_runner(runner.clone(), || addons::wait(&addon, "kubeconfig", None)).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "kubectl --kubeconfig kubeconfig get customresourcedefinition certificates.cert-manager.io -o json",
+                "kubectl --kubeconfig kubeconfig get deployment --namespace cert-manager cert-manager-webhook -o json",
+                "kubectl --kubeconfig kubeconfig get validatingwebhookconfigurations,mutatingwebhookconfigurations \
+                 --field-selector metadata.name=cert-manager-webhook -o json",
+                "kubectl --kubeconfig kubeconfig get endpoints --namespace cert-manager cert-manager-webhook -o json",
+                "kubectl --kubeconfig kubeconfig create --dry-run=server -f -",
+            ]
+        );
+
+        let runner = Rc::new(
+            FakeRunner::new()
+                .on("get customresourcedefinition", crd)
+                .on("get deployment", &deployment(0))
+                .on("get validatingwebhookconfigurations", webhooks)
+                .on("get endpoints", r#"{"subsets": []}"#)
+                .fail(
+                    "create --dry-run=server",
+                    r#"Error from server (InternalError): failed calling webhook "webhook.cert-manager.io""#,
+                ),
+        );
+        let err = cmd::with_runner(runner, || {
+            addons::wait(&addon, "kubeconfig", Some(Duration::from_secs(0)))
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 0s waiting for cert-manager: \
+             deployment cert-manager-webhook has 0/1 replicas available, \
+             webhook service cert-manager-webhook has no ready endpoints, \
+             probe: Error from server (InternalError): failed calling webhook \"webhook.cert-manager.io\""
+        );
+    }
+
+    #[test]
+    fn test_reversed() {
+        let manifest = "# cert-manager\n---\napiVersion: v1\nkind: Namespace\n---\n\
+                        apiVersion: v1\nkind: ServiceAccount\n---\n";
+
+        assert_eq!(
+            addons::reversed(manifest),
+            "apiVersion: v1\nkind: ServiceAccount\n---\napiVersion: v1\nkind: Namespace\n"
+        );
+    }
+}
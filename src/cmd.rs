@@ -0,0 +1,355 @@
+// Runs the external commands hake depends on (kind, kubectl, docker...),
+// turning failures into errors that tell what was run and what it said.
+
+use anyhow::{anyhow, Context, Result};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::wait;
+
+/// Lines of stderr kept in a `CommandError`.
+const STDERR_TAIL: usize = 20;
+
+#[derive(Debug)]
+pub struct CommandError {
+    /// The command line, like `kind create cluster --name test`.
+    pub command: String,
+    /// None if the command was killed by a signal.
+    pub code: Option<i32>,
+    /// Last lines of stderr.
+    pub stderr: String,
+}
+
+impl CommandError {
+    /// Last line of stderr, usually the most useful one.
+    pub fn reason(&self) -> &str {
+        self.stderr.lines().last().unwrap_or("")
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "`{}` failed with exit code {}", self.command, code)?,
+            None => write!(f, "`{}` was terminated by a signal", self.command)?,
+        }
+        if !self.stderr.is_empty() {
+            write!(f, ":\n{}", self.stderr)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub fn command_line(program: &str, args: &[&str]) -> String {
+    let mut line = vec![String::from(program)];
+    for arg in args {
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            line.push(format!("'{}'", arg));
+        } else {
+            line.push(String::from(*arg));
+        }
+    }
+
+    line.join(" ")
+}
+
+fn tail(stderr: &str, lines: usize) -> String {
+    let all: Vec<&str> = stderr.trim_end().lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+fn check(program: &str, args: &[&str], output: Output) -> Result<String> {
+    if !output.status.success() {
+        return Err(CommandError {
+            command: command_line(program, args),
+            code: output.status.code(),
+            stderr: tail(&String::from_utf8_lossy(&output.stderr), STDERR_TAIL),
+        }
+        .into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs external commands. Tests replace the system runner with a fake one
+/// using `with_runner`.
+pub trait CommandRunner {
+    /// Runs `program` with `env` added to its environment, writing `input`
+    /// to its stdin if given, and returns its stdout. `program` is killed
+    /// if it runs for longer than `timeout`.
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(&str, &str)],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<String>;
+
+    /// Runs `program` with its output going to the terminal, so stderr is
+    /// not part of the error.
+    fn run_attached(&self, program: &str, args: &[&str], env: &[(&str, &str)]) -> Result<()>;
+}
+
+/// Reads `pipe` to the end in another thread, so a child filling one pipe
+/// doesn't block while it's waited for.
+fn read_in_background<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer)?;
+        }
+        Ok(buffer)
+    })
+}
+
+/// Waits for `child` like `wait_with_output`, killing it after `timeout`.
+/// Returns None if it was killed.
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> io::Result<Option<Output>> {
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let join = |h: thread::JoinHandle<io::Result<Vec<u8>>>| {
+                h.join().unwrap_or_else(|_| Ok(vec![]))
+            };
+            return Ok(Some(Output {
+                status,
+                stdout: join(stdout)?,
+                stderr: join(stderr)?,
+            }));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs commands for real.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(&str, &str)],
+        input: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<String> {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .envs(env.iter().copied())
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let output = command
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(input) = input {
+                    child
+                        .stdin
+                        .take()
+                        .expect("stdin is piped")
+                        .write_all(input.as_bytes())?;
+                }
+                match timeout {
+                    Some(timeout) => wait_with_timeout(child, timeout),
+                    None => child.wait_with_output().map(Some),
+                }
+            })
+            .with_context(|| format!("Could not run {}", program))?;
+
+        match output {
+            Some(output) => check(program, args, output),
+            None => Err(anyhow!(
+                "`{}` did not finish within {}",
+                command_line(program, args),
+                wait::format_duration(timeout.unwrap_or_default())
+            )),
+        }
+    }
+
+    fn run_attached(&self, program: &str, args: &[&str], env: &[(&str, &str)]) -> Result<()> {
+        let status = Command::new(program)
+            .args(args)
+            .envs(env.iter().copied())
+            .status()
+            .with_context(|| format!("Could not run {}", program))?;
+
+        if !status.success() {
+            return Err(CommandError {
+                command: command_line(program, args),
+                code: status.code(),
+                stderr: String::new(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+thread_local! {
+    static RUNNER: RefCell<Rc<dyn CommandRunner>> = RefCell::new(Rc::new(SystemRunner));
+}
+
+fn runner() -> Rc<dyn CommandRunner> {
+    RUNNER.with(|r| r.borrow().clone())
+}
+
+/// Puts the previous runner back when dropped, even if the test panics.
+#[cfg(test)]
+struct RestoreRunner(Option<Rc<dyn CommandRunner>>);
+
+#[cfg(test)]
+impl Drop for RestoreRunner {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            RUNNER.with(|r| r.replace(previous));
+        }
+    }
+}
+
+/// Runs `f` with the commands run by this thread going through `runner`.
+#[cfg(test)]
+pub fn with_runner<T, F: FnOnce() -> T>(runner: Rc<dyn CommandRunner>, f: F) -> T {
+    let _restore = RestoreRunner(Some(RUNNER.with(|r| r.replace(runner))));
+
+    f()
+}
+
+/// Runs `program` and returns its stdout.
+pub fn run(program: &str, args: &[&str]) -> Result<String> {
+    runner().run(program, args, &[], None, None)
+}
+
+/// Runs `program` with `env` added to its environment, and returns its
+/// stdout.
+pub fn run_with_env(program: &str, args: &[&str], env: &[(&str, &str)]) -> Result<String> {
+    runner().run(program, args, env, None, None)
+}
+
+/// Runs `program` writing `input` to its stdin, and returns its stdout.
+pub fn run_with_input(program: &str, args: &[&str], input: &str) -> Result<String> {
+    runner().run(program, args, &[], Some(input), None)
+}
+
+/// Runs `program` writing `input` to its stdin, and returns its stdout.
+/// Fails if it doesn't finish within `timeout`.
+pub fn run_with_timeout(
+    program: &str,
+    args: &[&str],
+    input: &str,
+    timeout: Duration,
+) -> Result<String> {
+    runner().run(program, args, &[], Some(input), Some(timeout))
+}
+
+/// Runs `program` with its output going to the terminal.
+pub fn run_attached(program: &str, args: &[&str]) -> Result<()> {
+    runner().run_attached(program, args, &[])
+}
+
+/// Runs `program` with `env` added to its environment and its output going
+/// to the terminal.
+pub fn run_attached_with_env(program: &str, args: &[&str], env: &[(&str, &str)]) -> Result<()> {
+    runner().run_attached(program, args, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd::{self, CommandError};
+    use crate::testing::FakeRunner;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_run() {
+        assert_eq!(cmd::run("sh", &["-c", "echo hello"]).unwrap(), "hello\n");
+        assert_eq!(cmd::run_with_input("cat", &[], "input").unwrap(), "input");
+        assert_eq!(
+            cmd::run_with_env("sh", &["-c", "echo $HAKE_TEST"], &[("HAKE_TEST", "set")]).unwrap(),
+            "set\n"
+        );
+
+        let err = cmd::run(
+            "sh",
+            &["-c", "echo out; echo one >&2; echo two >&2; exit 3"],
+        )
+        .err()
+        .unwrap();
+        let command_err = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(command_err.code, Some(3));
+        assert_eq!(command_err.reason(), "two");
+        assert_eq!(
+            err.to_string(),
+            "`sh -c 'echo out; echo one >&2; echo two >&2; exit 3'` failed with exit code 3:\none\ntwo"
+        );
+
+        assert!(cmd::run("hake-does-not-exist", &[])
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Could not run hake-does-not-exist"));
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            cmd::run_with_timeout("cat", &[], "input", timeout).unwrap(),
+            "input"
+        );
+
+        let start = Instant::now();
+        let err = cmd::run_with_timeout("sleep", &["10"], "", Duration::from_millis(200))
+            .err()
+            .unwrap();
+        assert!(start.elapsed() < timeout);
+        assert!(err
+            .to_string()
+            .starts_with("`sleep 10` did not finish within"));
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(cmd::tail("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(cmd::tail("a\n", 2), "a");
+        assert_eq!(cmd::tail("", 2), "");
+    }
+
+    #[test]
+    fn test_with_runner_restores_on_panic() {
+        let runner = Rc::new(FakeRunner::new());
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cmd::with_runner(runner.clone(), || panic!("test failed"))
+        }));
+
+        assert!(panicked.is_err());
+        assert_eq!(Rc::strong_count(&runner), 1);
+        assert_eq!(cmd::run("sh", &["-c", "echo real"]).unwrap(), "real\n");
+    }
+}
@@ -2,23 +2,28 @@
 ///
 /// Digital Ocean Kubernetes
 ///
-use reqwest;
-use reqwest::header;
-use reqwest::header::{ACCEPT, CONTENT_TYPE};
-use reqwest::StatusCode;
+use anyhow::{anyhow, Context, Result};
 
-use anyhow::{anyhow, Result};
-use console::Style;
-
-use std::collections::{HashMap, HashSet};
-use std::fs::{create_dir, remove_dir_all, File};
-use std::io::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::time::Duration;
 use std::vec::Vec;
-use std::{env, io, thread, time};
 
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::provider::{self, ClusterInfo, CreateOptions, Provider, Status};
+use crate::rest::{self, parse_bool, parse_count, parse_list, Api};
+use crate::state::ClusterState;
+use crate::wait::{self, Progress};
+
 const ENV_DO_PROVIDER: &str = "HAKE_PROVIDER_DIGITALOCEAN_API_KEY";
+/// Base URL of the API, can be changed for proxies and tests.
+const ENV_DO_API_URL: &str = "HAKE_PROVIDER_DIGITALOCEAN_API_URL";
+const DEFAULT_API_URL: &str = "https://api.digitalocean.com/v2";
+
+/// Clusters usually take around 5 minutes to be running.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20 * 60);
 
 #[derive(Serialize, Deserialize, Debug)]
 struct NodeStatus {
@@ -35,6 +40,13 @@ struct Node {
     updated_at: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Taint {
+    key: String,
+    value: String,
+    effect: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct NodePool {
     id: Option<String>,
@@ -42,9 +54,31 @@ struct NodePool {
     size: String,
     count: u16,
     tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    labels: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    taints: Option<Vec<Taint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_scale: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_nodes: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_nodes: Option<u16>,
+    #[serde(default)]
     nodes: Vec<Node>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct MaintenancePolicy {
+    start_time: String,
+    day: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct KubernetesClusterStatus {
+    state: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct KubernetesCluster {
     id: Option<String>,
@@ -58,6 +92,15 @@ struct KubernetesCluster {
     endpoint: Option<String>,
     tags: Option<Vec<String>>,
     node_pools: Vec<NodePool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance_policy: Option<MaintenancePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ha: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    surge_upgrade: Option<bool>,
+    // only returned by the API, never sent
+    #[serde(skip_serializing)]
+    status: Option<KubernetesClusterStatus>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -77,17 +120,81 @@ struct LoadBalancer {
     droplet_ids: Vec<u32>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct LoadBalancerListResponse {
-    load_balancers: Vec<LoadBalancer>,
+const DEFAULT_NODEPOOL_SIZE: &str = "s-6vcpu-16gb";
+const DEFAULT_NODEPOOL_COUNT: u16 = 2;
+
+/// A node pool as described by the `nodepool.*` metadata keys. The default
+/// pool has no name.
+#[derive(Debug, PartialEq)]
+struct PoolMetadata {
+    name: Option<String>,
+    size: String,
+    count: u16,
+    labels: BTreeMap<String, String>,
+    taints: Vec<Taint>,
+    tags: Vec<String>,
+    auto_scale: bool,
+    min_nodes: Option<u16>,
+    max_nodes: Option<u16>,
+}
+
+impl PoolMetadata {
+    fn new(name: Option<String>) -> PoolMetadata {
+        PoolMetadata {
+            name,
+            size: String::from(DEFAULT_NODEPOOL_SIZE),
+            count: DEFAULT_NODEPOOL_COUNT,
+            labels: BTreeMap::new(),
+            taints: vec![],
+            tags: vec![],
+            auto_scale: false,
+            min_nodes: None,
+            max_nodes: None,
+        }
+    }
+
+    /// Key prefix of this pool, used in error messages.
+    fn prefix(&self) -> String {
+        match &self.name {
+            Some(name) => format!("nodepool.{}", name),
+            None => String::from("nodepool"),
+        }
+    }
+
+    fn to_node_pool(&self, cluster: &str) -> NodePool {
+        NodePool {
+            name: match &self.name {
+                Some(name) => name.clone(),
+                None => format!("nodepool-{}", cluster),
+            },
+            size: self.size.clone(),
+            count: self.count,
+            tags: non_empty(&self.tags),
+            labels: if self.labels.is_empty() {
+                None
+            } else {
+                Some(self.labels.clone())
+            },
+            taints: non_empty(&self.taints),
+            auto_scale: if self.auto_scale { Some(true) } else { None },
+            min_nodes: self.min_nodes,
+            max_nodes: self.max_nodes,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Metadata {
     region: String,
     version: String,
-    nodepool_size: String,
-    nodepool_count: u16,
+    vpc_uuid: Option<String>,
+    ha: bool,
+    surge_upgrade: bool,
+    maintenance_day: Option<String>,
+    maintenance_start_time: Option<String>,
+    tags: Vec<String>,
+    node_pools: Vec<PoolMetadata>,
 }
 
 impl Default for Metadata {
@@ -95,284 +202,821 @@ impl Default for Metadata {
         Metadata {
             region: "lon1".to_string(),
             version: "1.17.6-do.0".to_string(),
-            nodepool_size: "s-6vcpu-16gb".to_string(),
-            nodepool_count: 2,
+            vpc_uuid: None,
+            ha: false,
+            surge_upgrade: false,
+            maintenance_day: None,
+            maintenance_start_time: None,
+            tags: vec![],
+            node_pools: vec![],
         }
     }
 }
 
+fn non_empty<T: Clone>(values: &[T]) -> Option<Vec<T>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
+    }
+}
+
+/// Parses labels like `disk=ssd,tier=db`.
+fn parse_labels(key: &str, value: &str) -> Result<BTreeMap<String, String>, String> {
+    parse_list(value)
+        .iter()
+        .map(|label| {
+            let mut parts = label.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if !k.is_empty() => Ok((String::from(k), String::from(v))),
+                _ => Err(format!(
+                    "{}: invalid label \"{}\", expected key=value",
+                    key, label
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Parses taints like `dedicated=db:NoSchedule,gpu:NoExecute`.
+fn parse_taints(key: &str, value: &str) -> Result<Vec<Taint>, String> {
+    let re = Regex::new(r"^([^=:]+)(=([^=:]*))?:(NoSchedule|PreferNoSchedule|NoExecute)$").unwrap();
+
+    parse_list(value)
+        .iter()
+        .map(|taint| {
+            let cap = re.captures(taint).ok_or_else(|| {
+                format!(
+                    "{}: invalid taint \"{}\", expected key[=value]:NoSchedule|PreferNoSchedule|NoExecute",
+                    key, taint
+                )
+            })?;
+
+            Ok(Taint {
+                key: String::from(&cap[1]),
+                value: cap.get(3).map(|v| v.as_str()).unwrap_or("").to_string(),
+                effect: String::from(&cap[4]),
+            })
+        })
+        .collect()
+}
+
 impl Metadata {
-    pub fn from_string(data: &str) -> Metadata {
+    /// Parses metadata like `region=ams3&nodepool.count=3`. Keys of the form
+    /// `nodepool.<attr>` configure the default node pool and
+    /// `nodepool.<name>.<attr>` configure extra named pools. The default pool
+    /// is only created if no named pools are given or it is configured
+    /// explicitly. `--k8s-version` and `--workers` set the version and the
+    /// size of the default pool, unless `version` and `nodepool.count` are
+    /// given.
+    pub fn from_options(options: &CreateOptions) -> Result<Metadata> {
+        let data = options.metadata.as_deref().unwrap_or_default();
         let mut metadata = Metadata::default();
-        let map = parse_metadata(data);
-
-        for (key, value) in map {
-            match &key[..] {
-                "region" => metadata.region = value,
-                "version" => metadata.version = value,
-                "nodepool.size" => metadata.nodepool_size = value,
-                "nodepool.count" => metadata.nodepool_count = value.parse::<u16>().unwrap(),
-                _ => {}
+        let mut errors = rest::invalid_fields(data);
+        let mut pools: BTreeMap<Option<String>, PoolMetadata> = BTreeMap::new();
+
+        if let Some(version) = &options.k8s_version {
+            let re = Regex::new(r"^\d+\.\d+\.\d+-do\.\d+$").unwrap();
+            if re.is_match(version) {
+                metadata.version = version.clone();
+            } else {
+                errors.push(format!(
+                    "k8s_version: expected a DigitalOcean version like 1.27.4-do.0, got \"{}\"",
+                    version
+                ));
+            }
+        }
+        if let Some(workers) = options.workers {
+            match u16::try_from(workers) {
+                Ok(count) => {
+                    let mut pool = PoolMetadata::new(None);
+                    pool.count = count;
+                    pools.insert(None, pool);
+                }
+                Err(_) => errors.push(format!("workers: must be at most {}", u16::MAX)),
+            }
+        }
+
+        let map = rest::parse_metadata(data);
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+
+        let name_re = Regex::new(r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?$").unwrap();
+        let time_re = Regex::new(r"^([01][0-9]|2[0-3]):[0-5][0-9]$").unwrap();
+        for key in keys {
+            let value = &map[key][..];
+            let parts: Vec<&str> = key.split('.').collect();
+
+            let result = match &parts[..] {
+                ["region"] => {
+                    metadata.region = String::from(value);
+                    Ok(())
+                }
+                ["version"] => {
+                    metadata.version = String::from(value);
+                    Ok(())
+                }
+                ["vpc_uuid"] => {
+                    metadata.vpc_uuid = Some(String::from(value));
+                    Ok(())
+                }
+                ["ha"] => parse_bool(key, value).map(|v| metadata.ha = v),
+                ["surge_upgrade"] => parse_bool(key, value).map(|v| metadata.surge_upgrade = v),
+                ["tags"] => {
+                    metadata.tags = parse_list(value);
+                    Ok(())
+                }
+                ["maintenance", "day"] => {
+                    let days = [
+                        "any",
+                        "monday",
+                        "tuesday",
+                        "wednesday",
+                        "thursday",
+                        "friday",
+                        "saturday",
+                        "sunday",
+                    ];
+                    if days.contains(&value) {
+                        metadata.maintenance_day = Some(String::from(value));
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "{}: expected one of {}, got \"{}\"",
+                            key,
+                            days.join(", "),
+                            value
+                        ))
+                    }
+                }
+                ["maintenance", "start_time"] => {
+                    if time_re.is_match(value) {
+                        metadata.maintenance_start_time = Some(String::from(value));
+                        Ok(())
+                    } else {
+                        Err(format!("{}: expected HH:MM, got \"{}\"", key, value))
+                    }
+                }
+                ["nodepool", attr] | ["nodepool", _, attr] => {
+                    let name = if parts.len() == 3 {
+                        Some(String::from(parts[1]))
+                    } else {
+                        None
+                    };
+                    match &name {
+                        Some(name) if !name_re.is_match(name) => Err(format!(
+                            "{}: invalid node pool name \"{}\", it must consist of lower case alphanumeric characters or '-'",
+                            key, name
+                        )),
+                        _ => {
+                            let pool = pools
+                                .entry(name.clone())
+                                .or_insert_with(|| PoolMetadata::new(name));
+                            match *attr {
+                                "size" => {
+                                    pool.size = String::from(value);
+                                    Ok(())
+                                }
+                                "count" => parse_count(key, value).map(|v| pool.count = v),
+                                "labels" => parse_labels(key, value).map(|v| pool.labels = v),
+                                "taints" => parse_taints(key, value).map(|v| pool.taints = v),
+                                "tags" => {
+                                    pool.tags = parse_list(value);
+                                    Ok(())
+                                }
+                                "auto_scale" => parse_bool(key, value).map(|v| pool.auto_scale = v),
+                                "min_nodes" => {
+                                    parse_count(key, value).map(|v| pool.min_nodes = Some(v))
+                                }
+                                "max_nodes" => {
+                                    parse_count(key, value).map(|v| pool.max_nodes = Some(v))
+                                }
+                                _ => Err(format!("{}: unknown node pool setting", key)),
+                            }
+                        }
+                    }
+                }
+                _ => Err(format!("{}: unknown metadata key", key)),
+            };
+
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+
+        if metadata.maintenance_start_time.is_some() != metadata.maintenance_day.is_some() {
+            errors.push(String::from(
+                "maintenance: both maintenance.day and maintenance.start_time are needed",
+            ));
+        }
+
+        if pools.is_empty() {
+            pools.insert(None, PoolMetadata::new(None));
+        }
+        for pool in pools.values() {
+            let prefix = pool.prefix();
+            if pool.auto_scale {
+                match (pool.min_nodes, pool.max_nodes) {
+                    (Some(min), Some(max)) if min <= max => {}
+                    (Some(_), Some(_)) => errors.push(format!(
+                        "{}.min_nodes: must not be greater than {}.max_nodes",
+                        prefix, prefix
+                    )),
+                    _ => errors.push(format!(
+                        "{}.auto_scale: needs {}.min_nodes and {}.max_nodes",
+                        prefix, prefix, prefix
+                    )),
+                }
+            } else if pool.min_nodes.is_some() || pool.max_nodes.is_some() {
+                errors.push(format!(
+                    "{}.min_nodes: only used when {}.auto_scale=true",
+                    prefix, prefix
+                ));
+            } else if pool.count == 0 {
+                errors.push(format!("{}.count: must be at least 1", prefix));
             }
         }
 
-        metadata
+        rest::metadata_errors("DigitalOcean", errors)?;
+
+        metadata.node_pools = pools.into_values().collect();
+
+        Ok(metadata)
     }
 }
 
-pub fn create(name: &str, metadata: Option<String>) -> Result<()> {
-    let provider_metadata = metadata.unwrap_or("".to_string());
-    let cluster_spec = Metadata::from_string(&provider_metadata);
+fn api() -> Result<Api> {
+    Api::new(
+        "digitalocean",
+        ENV_DO_PROVIDER,
+        ENV_DO_API_URL,
+        DEFAULT_API_URL,
+    )
+}
+
+pub fn create(state: &mut ClusterState) -> Result<()> {
+    let name = &state.name;
+    let cluster_spec = Metadata::from_options(&state.options)?;
 
     let new_cluster = KubernetesCluster {
         id: None,
         name: String::from(name),
         region: cluster_spec.region,
         version: cluster_spec.version,
-        node_pools: vec![NodePool {
-            size: cluster_spec.nodepool_size,
-            count: cluster_spec.nodepool_count,
-            name: format!("nodepool-{}", &name),
-            ..Default::default()
-        }],
+        vpc_uuid: cluster_spec.vpc_uuid,
+        tags: non_empty(&cluster_spec.tags),
+        node_pools: cluster_spec
+            .node_pools
+            .iter()
+            .map(|pool| pool.to_node_pool(name))
+            .collect(),
+        maintenance_policy: match (
+            cluster_spec.maintenance_start_time,
+            cluster_spec.maintenance_day,
+        ) {
+            (Some(start_time), Some(day)) => Some(MaintenancePolicy { start_time, day }),
+            _ => None,
+        },
+        ha: Some(cluster_spec.ha),
+        surge_upgrade: Some(cluster_spec.surge_upgrade),
         ..Default::default()
     };
 
-    let client = get_do_api_client()?;
-    let resp = client
-        .post("https://api.digitalocean.com/v2/kubernetes/clusters")
-        .header(CONTENT_TYPE, "application/json")
-        .json(&new_cluster)
-        .send()?;
-
-    if resp.status() != StatusCode::CREATED {
-        println!("{:?}", &resp.text()?.to_string());
-        return Err(anyhow!("Could not create cluster:"));
+    let api = api()?;
+    let json_response: KubernetesClusterResponse = api
+        .post("/kubernetes/clusters", &new_cluster)
+        .context("Could not create cluster")?;
+
+    let cluster_id = json_response
+        .kubernetes_cluster
+        .id
+        .ok_or_else(|| anyhow!("DigitalOcean created cluster {} without an id", name))?;
+    log::info!("Cluster created with id: {}", cluster_id);
+
+    // record the id straight away, so the cluster can be deleted even if
+    // getting its kubeconfig fails.
+    state.remote_id = Some(cluster_id.clone());
+    state.save()?;
+
+    let timeout = state.options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    if !state.options.no_wait {
+        wait::until(&format!("cluster {}", name), timeout, || {
+            rest::retry_transient(match get_cluster(&api, &cluster_id) {
+                Ok(Some(cluster)) => provisioning_progress(&cluster),
+                Ok(None) => Err(anyhow!("Cluster with id {} was not found", cluster_id)),
+                Err(e) => Err(e),
+            })
+        })?;
     }
 
-    let json_response: KubernetesClusterResponse = resp.json()?;
+    // The kubeconfig is available a few seconds after the cluster is
+    // requested, even if it is still provisioning.
+    api.download_kubeconfig(
+        &format!("/kubernetes/clusters/{}/kubeconfig", cluster_id),
+        &format!("{}/kubeconfig", state.dir()),
+        timeout,
+        Ok,
+    )
+}
 
-    let cluster_id = json_response.kubernetes_cluster.id.unwrap();
-    let cyan = Style::new().cyan();
-    println!("Cluster created with id: {}", cyan.apply_to(&cluster_id));
+/// The cluster is ready once it is running and so are all of its nodes.
+fn provisioning_progress(cluster: &KubernetesCluster) -> Result<Progress> {
+    let state = cluster
+        .status
+        .as_ref()
+        .map(|s| &s.state[..])
+        .unwrap_or("unknown");
+    match state {
+        "running" => {}
+        "error" | "deleted" | "invalid" => {
+            return Err(anyhow!("Cluster {} is in state {}", cluster.name, state))
+        }
+        _ => return Ok(Progress::Pending(format!("cluster is {}", state))),
+    }
 
-    let cluster_dir = format!("{}/{}", crate::get_config_dir(), name);
-    create_dir(&cluster_dir)?;
+    let expected: usize = cluster.node_pools.iter().map(|np| np.count as usize).sum();
+    let running = cluster
+        .node_pools
+        .iter()
+        .flat_map(|np| np.nodes.iter())
+        .filter(|node| node.status.state == "running")
+        .count();
+    if running < expected {
+        return Ok(Progress::Pending(format!(
+            "{}/{} nodes running",
+            running, expected
+        )));
+    }
 
-    let url = format!(
-        "https://api.digitalocean.com/v2/kubernetes/clusters/{}/kubeconfig",
-        &cluster_id
-    );
+    Ok(Progress::Done)
+}
 
-    // need to wait for the server to be "prepared"
-    thread::sleep(time::Duration::from_secs(10));
+fn get_cluster(api: &Api, cluster_id: &str) -> Result<Option<KubernetesCluster>> {
+    let resp: Option<KubernetesClusterResponse> = api
+        .get(&format!("/kubernetes/clusters/{}", cluster_id))
+        .with_context(|| format!("Could not get Cluster with id: {}", cluster_id))?;
 
-    let mut resp = client
-        .get(&url)
-        .header(CONTENT_TYPE, "application/json")
-        .send()?;
+    Ok(resp.map(|r| r.kubernetes_cluster))
+}
 
-    let mut out =
-        File::create(format!("{}/kubeconfig", &cluster_dir)).expect("failed to create file");
-    io::copy(&mut resp, &mut out).expect("failed to copy content");
+// Return the droplets of the nodes of a cluster
+fn get_droplets_ids_for_cluster(cluster: &KubernetesCluster) -> Result<HashSet<u32>> {
+    let mut droplet_ids = HashSet::new();
+    for node_pool in cluster.node_pools.iter() {
+        for node in node_pool.nodes.iter() {
+            if let Some(id) = &node.droplet_id {
+                let id = id.parse::<u32>().with_context(|| {
+                    format!("Invalid droplet id for node {}: {}", node.name, id)
+                })?;
+                droplet_ids.insert(id);
+            }
+        }
+    }
 
-    let mut cluster_uuid = File::create(format!("{}/cluster_uuid", &cluster_dir))?;
+    Ok(droplet_ids)
+}
 
-    cluster_uuid.write_all(&cluster_id.as_bytes())?;
+fn get_load_balancer_pointing_at_droplet_id(
+    api: &Api,
+    droplet_ids: HashSet<u32>,
+) -> Result<Vec<LoadBalancer>> {
+    let load_balancers: Vec<LoadBalancer> = api.list("/load_balancers", "load_balancers")?;
 
-    Ok(())
+    Ok(load_balancers
+        .into_iter()
+        .filter(|lb| lb.droplet_ids.iter().cloned().collect::<HashSet<u32>>() == droplet_ids)
+        .collect())
 }
 
-// Return a list of droplets for a given cluster
-fn get_droplets_ids_for_cluster(cluster_id: &str) -> Result<Vec<u32>> {
-    let client = get_do_api_client()?;
-    let resp = client
-        .get(&format!(
-            "https://api.digitalocean.com/v2/kubernetes/clusters/{}",
-            cluster_id
-        ))
-        .header(ACCEPT, "application/json")
-        .send()?;
+fn delete_load_balancer(api: &Api, lb: LoadBalancer) -> Result<()> {
+    let lb_id = lb
+        .id
+        .as_ref()
+        .ok_or_else(|| anyhow!("Got an empty id for Load Balancer {}", lb.name))?;
+    log::info!("Removing Load Balancer: {}", lb_id);
 
-    let json_response: KubernetesClusterResponse = resp.json()?;
+    api.delete(&format!("/load_balancers/{}", lb_id))
+        .with_context(|| format!("Could not remove Load Balancer with id: {}", lb_id))
+}
 
-    let mut droplet_ids: Vec<u32> = vec![];
-    for node_pool in json_response.kubernetes_cluster.node_pools.iter() {
-        for node in node_pool.nodes.iter() {
-            if let Some(id) = &node.droplet_id {
-                droplet_ids.push(id.parse::<u32>().unwrap_or(0))
-            }
+fn delete_residuals(api: &Api, cluster_id: &str) -> Result<()> {
+    let cluster = match get_cluster(api, cluster_id)? {
+        Some(cluster) => cluster,
+        None => return Ok(()),
+    };
+    let droplet_ids = get_droplets_ids_for_cluster(&cluster)?;
+    // clusters without droplets yet would match every load balancer that
+    // has none either
+    if droplet_ids.is_empty() {
+        return Ok(());
+    }
+
+    if let Ok(lbs) = get_load_balancer_pointing_at_droplet_id(api, droplet_ids) {
+        for lb in lbs {
+            delete_load_balancer(api, lb)?;
         }
     }
 
-    Ok(droplet_ids)
+    Ok(())
 }
 
-fn get_api_token() -> Result<String> {
-    Ok(env::var(ENV_DO_PROVIDER)?)
+fn get_cluster_id(state: &ClusterState) -> Result<&str> {
+    state
+        .remote_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("Cluster {} has no DigitalOcean id", state.name))
 }
 
-fn auth_headers() -> Result<reqwest::header::HeaderMap> {
-    let api_key = get_api_token()?;
-    let bearer_auth = format!("Bearer {}", &api_key);
+pub fn delete(state: &ClusterState) -> Result<()> {
+    let cluster_id = get_cluster_id(state)?;
+    let api = api()?;
 
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(&bearer_auth)?,
-    );
+    delete_residuals(&api, cluster_id)?;
 
-    Ok(headers)
+    log::info!("Removing Cluster: {}", cluster_id);
+    api.delete(&format!("/kubernetes/clusters/{}", cluster_id))
+        .with_context(|| format!("Could not remove Cluster with id: {}", cluster_id))
 }
 
-fn get_do_api_client() -> Result<reqwest::blocking::Client> {
-    Ok(reqwest::blocking::Client::builder()
-        .default_headers(auth_headers()?)
-        .build()?)
+/// Waits for a deleted cluster to be gone, so a new one can take its name.
+fn wait_until_deleted(state: &ClusterState) -> Result<()> {
+    let cluster_id = get_cluster_id(state)?;
+    let api = api()?;
+
+    let timeout = state.options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    wait::until(
+        &format!("cluster {} to be deleted", state.name),
+        timeout,
+        || {
+            rest::retry_transient(match get_cluster(&api, cluster_id) {
+                Ok(Some(cluster)) => Ok(Progress::Pending(format!(
+                    "cluster is {}",
+                    cluster.status.map(|s| s.state).unwrap_or_default()
+                ))),
+                Ok(None) => Ok(Progress::Done),
+                Err(e) => Err(e),
+            })
+        },
+    )
 }
 
-fn get_load_balancer_pointing_at_droplet_id(
-    droplet_ids: HashSet<u32>,
-) -> Result<Vec<LoadBalancer>> {
-    let client = get_do_api_client()?;
-    let resp = client
-        .get("https://api.digitalocean.com/v2/load_balancers")
-        .header(ACCEPT, "application/json")
-        .send()?;
+fn get_info(state: &ClusterState) -> Result<ClusterInfo> {
+    let cluster_id = get_cluster_id(state)?;
 
-    let load_balancers: LoadBalancerListResponse = resp.json()?;
+    let cluster = match get_cluster(&api()?, cluster_id)? {
+        Some(cluster) => cluster,
+        None => return Ok(ClusterInfo::from(Status::NotFound)),
+    };
+    let status = match cluster.status {
+        Some(status) if status.state == "running" => Status::Running,
+        Some(status) => Status::Provisioning(status.state),
+        None => Status::Provisioning(String::from("unknown")),
+    };
 
-    Ok(load_balancers
-        .load_balancers
-        .into_iter()
-        .filter(|lb| lb.droplet_ids.iter().cloned().collect::<HashSet<u32>>() == droplet_ids)
-        .collect())
+    Ok(ClusterInfo {
+        status,
+        version: Some(cluster.version),
+        nodes: Some(cluster.node_pools.iter().map(|np| np.count as usize).sum()),
+    })
 }
 
-fn delete_load_balancer(lb: LoadBalancer) -> Result<()> {
-    let lb_id = lb.id.expect("Got an empty id for load_balancer");
-    let cyan = Style::new().cyan();
-    println!("Removing Load Balancer: {}", cyan.apply_to(&lb_id));
+fn list_clusters() -> Result<Vec<String>> {
+    let clusters: Vec<KubernetesCluster> =
+        api()?.list("/kubernetes/clusters", "kubernetes_clusters")?;
 
-    let client = get_do_api_client()?;
-    let resp = client
-        .delete(&format!(
-            "https://api.digitalocean.com/v2/load_balancers/{}",
-            lb_id
-        ))
-        .send()?;
-
-    if resp.status() == StatusCode::NO_CONTENT {
-        Ok(())
-    } else {
-        Err(anyhow!(
-            "Could not remove Load Balancer with id: {}. Status code is: {}",
-            lb_id,
-            resp.status()
-        ))
-    }
+    Ok(clusters.into_iter().map(|c| c.name).collect())
 }
 
-fn delete_residuals(cluster_id: &str) -> Result<()> {
-    let droplet_ids: HashSet<u32> = get_droplets_ids_for_cluster(&cluster_id)?
-        .into_iter()
-        .collect();
+pub struct DigitalOcean;
 
-    let lbs = get_load_balancer_pointing_at_droplet_id(droplet_ids);
-    match lbs {
-        Ok(lbs) => {
-            for lb in lbs {
-                delete_load_balancer(lb)?;
-            }
-        }
-        _ => {}
+impl Provider for DigitalOcean {
+    fn name(&self) -> &str {
+        "digitalocean"
     }
 
-    Ok(())
-}
+    fn aliases(&self) -> &'static [&'static str] {
+        &["do"]
+    }
+
+    fn validate(&self, _name: &str, options: &CreateOptions) -> Result<()> {
+        provider::reject_unsupported(self.name(), &provider::managed_options(options))?;
+        Metadata::from_options(options)?;
 
-pub fn delete(name: &str) -> Result<()> {
-    let config_dir = crate::get_config_dir();
-
-    let doid = format!("{}/{}/cluster_uuid", config_dir, name);
-    let mut file = File::open(doid)?;
-    let mut cluster_id = String::new();
-    file.read_to_string(&mut cluster_id)?;
-
-    delete_residuals(&cluster_id)?;
-
-    let cyan = Style::new().cyan();
-    println!("Removing Cluster: {}", cyan.apply_to(&cluster_id));
-    let client = get_do_api_client()?;
-    let resp = client
-        .delete(&format!(
-            "https://api.digitalocean.com/v2/kubernetes/clusters/{}",
-            cluster_id
-        ))
-        .send()?;
-
-    if resp.status() != StatusCode::NO_CONTENT {
-        return Err(anyhow!(
-            "Could not remove Cluster with id: {}. Status code is: {}",
-            &cluster_id,
-            resp.status()
-        ));
+        Ok(())
     }
 
-    remove_dir_all(format!("{}/{}", config_dir, name))?;
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        create(state)
+    }
 
-    Ok(())
-}
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        delete(state)
+    }
 
-fn parse_metadata(metadata: &str) -> HashMap<String, String> {
-    let fields: Vec<&str> = metadata.split("&").collect();
-    let mut map: HashMap<String, String> = HashMap::new();
+    /// DigitalOcean refuses new clusters named like one being deleted.
+    fn recreate(&self, state: &mut ClusterState) -> Result<()> {
+        delete(state)?;
+        wait_until_deleted(state)?;
+        // so a failed create doesn't leave the old id behind
+        state.remote_id = None;
+        state.save()?;
 
-    // there should be a more idiomatic way of doing this!
-    for field in fields {
-        let split_field: Vec<&str> = field.split("=").into_iter().collect();
-        if split_field.len() != 2 {
-            continue;
-        }
+        create(state)
+    }
 
-        map.insert(split_field[0].to_string(), split_field[1].to_string());
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        Ok(get_info(state)?.status)
     }
 
-    map
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        get_info(state)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        list_clusters()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::r#do;
-    use std::collections::HashMap;
-
-    // Taken from https://stackoverflow.com/a/27582993/75928
-    macro_rules! map(
-    { $($key:expr => $value:expr),+ } => {
-        {
-            let mut m = ::std::collections::HashMap::new();
-            $(
-                m.insert($key, $value);
-            )+
-            m
-        }
-    };
-    );
+    use crate::provider::{CreateOptions, Provider};
+    use crate::r#do::{self, DigitalOcean};
+    use crate::state::ClusterState;
+    use crate::testing::{Env, MockServer};
+    use crate::wait::Progress;
+
+    fn from_metadata(data: &str) -> anyhow::Result<r#do::Metadata> {
+        r#do::Metadata::from_options(&CreateOptions {
+            metadata: Some(String::from(data)),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_metadata_from_options() {
+        let metadata = from_metadata(
+            "region=ams3&ha=true&maintenance.day=sunday&maintenance.start_time=03:00\
+             &nodepool.count=3\
+             &nodepool.db.size=s-4vcpu-8gb&nodepool.db.labels=disk=ssd,tier=db\
+             &nodepool.db.taints=dedicated=db:NoSchedule\
+             &nodepool.db.auto_scale=true&nodepool.db.min_nodes=1&nodepool.db.max_nodes=4",
+        )
+        .unwrap();
+
+        assert_eq!(metadata.region, "ams3");
+        assert!(metadata.ha);
+        assert!(!metadata.surge_upgrade);
+        assert_eq!(metadata.maintenance_day, Some(String::from("sunday")));
+        assert_eq!(metadata.node_pools.len(), 2);
+
+        let default = metadata.node_pools[0].to_node_pool("tests");
+        assert_eq!(default.name, "nodepool-tests");
+        assert_eq!(default.size, "s-6vcpu-16gb");
+        assert_eq!(default.count, 3);
+        assert_eq!(default.auto_scale, None);
+
+        let db = metadata.node_pools[1].to_node_pool("tests");
+        assert_eq!(db.name, "db");
+        assert_eq!(db.size, "s-4vcpu-8gb");
+        assert_eq!(db.labels.unwrap()["tier"], "db");
+        assert_eq!(
+            db.taints.unwrap(),
+            vec![r#do::Taint {
+                key: String::from("dedicated"),
+                value: String::from("db"),
+                effect: String::from("NoSchedule"),
+            }]
+        );
+        assert_eq!((db.min_nodes, db.max_nodes), (Some(1), Some(4)));
+
+        // only named pools, no default pool
+        let metadata = from_metadata("nodepool.web.count=1").unwrap();
+        assert_eq!(metadata.node_pools.len(), 1);
+        assert_eq!(metadata.node_pools[0].name, Some(String::from("web")));
+
+        let metadata = from_metadata("").unwrap();
+        assert_eq!(metadata.node_pools.len(), 1);
+        assert_eq!(metadata.node_pools[0].count, 2);
+
+        // the options set the version and the default pool, unless the
+        // metadata does
+        let mut options = CreateOptions {
+            workers: Some(3),
+            k8s_version: Some(String::from("1.27.4-do.0")),
+            metadata: Some(String::from("nodepool.web.count=1")),
+            ..Default::default()
+        };
+        let metadata = r#do::Metadata::from_options(&options).unwrap();
+        assert_eq!(metadata.version, "1.27.4-do.0");
+        let pools: Vec<(Option<&str>, u16)> = metadata
+            .node_pools
+            .iter()
+            .map(|p| (p.name.as_deref(), p.count))
+            .collect();
+        assert_eq!(pools, vec![(None, 3), (Some("web"), 1)]);
+
+        options.metadata = Some(String::from("version=1.26.7-do.0&nodepool.count=1"));
+        let metadata = r#do::Metadata::from_options(&options).unwrap();
+        assert_eq!(metadata.version, "1.26.7-do.0");
+        assert_eq!(metadata.node_pools[0].count, 1);
+    }
+
+    #[test]
+    fn test_metadata_from_options_errors() {
+        let err = from_metadata(
+            "region&nodepool.count=two&colour=blue&ha=yes&nodepool.db.auto_scale=true\
+             &nodepool.Big.size=s-1vcpu-2gb&maintenance.day=someday",
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            "Invalid DigitalOcean metadata:\
+             \n  - region: expected key=value\
+             \n  - colour: unknown metadata key\
+             \n  - ha: expected true or false, got \"yes\"\
+             \n  - maintenance.day: expected one of any, monday, tuesday, wednesday, thursday, friday, saturday, sunday, got \"someday\"\
+             \n  - nodepool.Big.size: invalid node pool name \"Big\", it must consist of lower case alphanumeric characters or '-'\
+             \n  - nodepool.count: expected a number, got \"two\"\
+             \n  - nodepool.db.auto_scale: needs nodepool.db.min_nodes and nodepool.db.max_nodes"
+        );
+
+        let options = CreateOptions {
+            workers: Some(70000),
+            k8s_version: Some(String::from("1.27")),
+            ..Default::default()
+        };
+        assert_eq!(
+            r#do::Metadata::from_options(&options)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Invalid DigitalOcean metadata:\
+             \n  - k8s_version: expected a DigitalOcean version like 1.27.4-do.0, got \"1.27\"\
+             \n  - workers: must be at most 65535"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let options = CreateOptions {
+            control_planes: Some(3),
+            port_mappings: vec![String::from("80:80")],
+            node_labels: vec![String::from("worker:disk=ssd")],
+            ecr: Some(String::from("1234.dkr.ecr.eu-west-1.amazonaws.com")),
+            local_registry: Some(String::from("hake-registry")),
+            ..Default::default()
+        };
+        assert_eq!(
+            DigitalOcean
+                .validate("tests", &options)
+                .err()
+                .unwrap()
+                .to_string(),
+            "The digitalocean provider does not support multiple control planes, \
+             node labels, port mappings, ECR registries, local registries"
+        );
+
+        let options = CreateOptions {
+            control_planes: Some(1),
+            workers: Some(3),
+            ..Default::default()
+        };
+        assert!(DigitalOcean.validate("tests", &options).is_ok());
+    }
 
     #[test]
-    fn test_parse_metadata() {
+    fn test_provisioning_progress() {
+        let cluster = |state: &str, nodes: &[&str]| -> r#do::KubernetesCluster {
+            let nodes: Vec<String> = nodes
+                .iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    format!(
+                        r#"{{"id": "{}", "name": "node-{}", "status": {{"state": "{}"}},
+                            "created_at": "", "updated_at": ""}}"#,
+                        i, i, s
+                    )
+                })
+                .collect();
+
+            serde_json::from_str(&format!(
+                r#"{{"name": "tests", "region": "lon1", "version": "1.27.4-do.0",
+                    "status": {{"state": "{}"}},
+                    "node_pools": [{{"name": "pool", "size": "s-1vcpu-2gb", "count": 2,
+                                     "nodes": [{}]}}]}}"#,
+                state,
+                nodes.join(",")
+            ))
+            .unwrap()
+        };
+        let pending = |cluster| match r#do::provisioning_progress(&cluster).unwrap() {
+            Progress::Pending(message) => message,
+            Progress::Done => String::from("done"),
+        };
+
+        assert_eq!(
+            pending(cluster("provisioning", &[])),
+            "cluster is provisioning"
+        );
         assert_eq!(
-            r#do::parse_metadata("region=lon1"),
-            map! { "region".to_string() => "lon1".to_string() }
+            pending(cluster("running", &["running", "provisioning"])),
+            "1/2 nodes running"
         );
+        assert_eq!(pending(cluster("running", &["running", "running"])), "done");
+        assert_eq!(
+            r#do::provisioning_progress(&cluster("error", &[]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Cluster tests is in state error"
+        );
+    }
 
+    #[test]
+    fn test_delete_residuals() {
+        let server = MockServer::start(|method, path, _| match (method, path) {
+            // still provisioning, without droplets
+            ("GET", "/v2/kubernetes/clusters/new") => (
+                200,
+                String::from(
+                    r#"{"kubernetes_cluster": {"id": "new", "name": "new", "region": "lon1",
+                        "version": "1.27.4-do.0", "status": {"state": "provisioning"},
+                        "node_pools": [{"name": "pool", "size": "s-1vcpu-2gb", "count": 1,
+                            "nodes": [{"id": "n1", "name": "n1", "status": {"state": "provisioning"},
+                                       "created_at": "", "updated_at": ""}]}]}}"#,
+                ),
+            ),
+            ("GET", "/v2/kubernetes/clusters/bad") => (
+                200,
+                String::from(
+                    r#"{"kubernetes_cluster": {"id": "bad", "name": "bad", "region": "lon1",
+                        "version": "1.27.4-do.0", "status": {"state": "running"},
+                        "node_pools": [{"name": "pool", "size": "s-1vcpu-2gb", "count": 1,
+                            "nodes": [{"id": "n1", "name": "n1", "status": {"state": "running"},
+                                       "droplet_id": "x42", "created_at": "", "updated_at": ""}]}]}}"#,
+                ),
+            ),
+            ("GET", "/v2/kubernetes/clusters/lb") => (
+                200,
+                String::from(
+                    r#"{"kubernetes_cluster": {"id": "lb", "name": "lb", "region": "lon1",
+                        "version": "1.27.4-do.0", "status": {"state": "running"},
+                        "node_pools": [{"name": "pool", "size": "s-1vcpu-2gb", "count": 1,
+                            "nodes": [{"id": "n1", "name": "n1", "status": {"state": "running"},
+                                       "droplet_id": "42", "created_at": "", "updated_at": ""}]}]}}"#,
+                ),
+            ),
+            ("GET", "/v2/load_balancers") => (
+                200,
+                String::from(
+                    r#"{"load_balancers": [{"id": "lb", "name": "other", "algorithm": "round_robin",
+                        "droplet_ids": []},
+                        {"name": "broken", "algorithm": "round_robin", "droplet_ids": [42]}]}"#,
+                ),
+            ),
+            ("DELETE", "/v2/kubernetes/clusters/new") => (204, String::new()),
+            _ => (404, String::new()),
+        });
+
+        let mut env = Env::new();
+        env.scratch_home("do-residuals");
+        env.set("HAKE_PROVIDER_DIGITALOCEAN_API_KEY", "token");
+        env.set(
+            "HAKE_PROVIDER_DIGITALOCEAN_API_URL",
+            &format!("{}/v2", server.url),
+        );
+        let state = |id: &str| {
+            let mut state =
+                ClusterState::new(id, "digitalocean", CreateOptions::default()).unwrap();
+            state.remote_id = Some(String::from(id));
+            state
+        };
+
+        // load balancers of other clusters are left alone
+        r#do::delete(&state("new")).unwrap();
+        assert!(r#do::delete(&state("gone")).is_err());
         assert_eq!(
-            r#do::parse_metadata("region=lon1&attr1=value1"),
-            map! { "region".to_string() => "lon1".to_string(), "attr1".to_string() => "value1".to_string() }
+            r#do::delete(&state("bad")).err().unwrap().to_string(),
+            "Invalid droplet id for node n1: x42"
+        );
+        assert_eq!(
+            r#do::delete(&state("lb")).err().unwrap().to_string(),
+            "Got an empty id for Load Balancer broken"
         );
 
-        assert_eq!(r#do::parse_metadata("region"), HashMap::new());
-        assert_eq!(r#do::parse_metadata("&"), HashMap::new());
-        assert_eq!(r#do::parse_metadata(""), HashMap::new());
+        assert_eq!(
+            server.requests(),
+            vec![
+                "GET /v2/kubernetes/clusters/new",
+                "DELETE /v2/kubernetes/clusters/new",
+                "GET /v2/kubernetes/clusters/gone",
+                "DELETE /v2/kubernetes/clusters/gone",
+                "GET /v2/kubernetes/clusters/bad",
+                "GET /v2/kubernetes/clusters/lb",
+                "GET /v2/load_balancers",
+            ]
+        );
     }
 }
@@ -0,0 +1,514 @@
+#![allow(non_snake_case)]
+
+// k3s clusters running in docker, created with k3d.
+
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::vec::Vec;
+
+use regex::Regex;
+
+use crate::cmd;
+use crate::kind::Kind;
+use crate::ports::{self, Protocol};
+use crate::provider::{self, ClusterInfo, CreateOptions, Provider, Status};
+use crate::registry;
+use crate::state::ClusterState;
+
+/// The k3s image used for each Kubernetes minor version.
+const K3S_IMAGES: &[(&str, &str)] = &[
+    ("1.24", "rancher/k3s:v1.24.15-k3s1"),
+    ("1.25", "rancher/k3s:v1.25.11-k3s1"),
+    ("1.26", "rancher/k3s:v1.26.6-k3s1"),
+    ("1.27", "rancher/k3s:v1.27.4-k3s1"),
+];
+
+#[derive(Serialize, Debug)]
+struct Metadata {
+    name: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Port {
+    /// Like `127.0.0.1:8080:80/tcp`.
+    port: String,
+    nodeFilters: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct RegistriesConfig {
+    /// Contents of the k3s `registries.yaml`.
+    config: String,
+}
+
+#[derive(Serialize, Debug)]
+struct KubeconfigOptions {
+    updateDefaultKubeconfig: bool,
+    switchCurrentContext: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Options {
+    kubeconfig: KubeconfigOptions,
+}
+
+#[derive(Serialize, Debug)]
+struct SimpleConfig {
+    apiVersion: String,
+    kind: String,
+    metadata: Metadata,
+    servers: u32,
+    agents: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    ports: Vec<Port>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registries: Option<RegistriesConfig>,
+    options: Options,
+}
+
+#[derive(Serialize, Debug)]
+struct Mirror {
+    endpoint: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct RegistryAuth {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, Debug)]
+struct RegistryConfig {
+    auth: RegistryAuth,
+}
+
+/// The k3s `registries.yaml`.
+#[derive(Serialize, Debug, Default)]
+struct Registries {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    mirrors: BTreeMap<String, Mirror>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    configs: BTreeMap<String, RegistryConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct K3dNode {
+    role: String,
+    #[serde(default)]
+    image: String,
+}
+
+/// A cluster in the output of `k3d cluster list -o json`.
+#[derive(Deserialize, Debug)]
+struct K3dCluster {
+    name: String,
+    #[serde(default)]
+    nodes: Vec<K3dNode>,
+}
+
+/// Resolves a version like `1.27` or `1.27.4` into a k3s image. k3s
+/// releases like `v1.27.4+k3s2` are used as given, as the k3s suffix of a
+/// Kubernetes version isn't always `k3s1`.
+fn k3s_image(version: &str) -> Result<String> {
+    let version = version.trim_start_matches('v');
+    let re = Regex::new(r"^(\d+\.\d+\.\d+)[-+](k3s\d+)$").unwrap();
+    if let Some(release) = re.captures(version) {
+        return Ok(format!("rancher/k3s:v{}-{}", &release[1], &release[2]));
+    }
+
+    let tag = format!("v{}", version);
+    K3S_IMAGES
+        .iter()
+        .find(|(minor, image)| {
+            *minor == version || image.rsplit(':').next().and_then(|t| t.split('-').next()) == Some(&tag[..])
+        })
+        .map(|(_, image)| String::from(*image))
+        .ok_or_else(|| {
+            let known: Vec<&str> = K3S_IMAGES.iter().map(|(minor, _)| *minor).collect();
+            anyhow!(
+                "No k3s image known for Kubernetes {}, use a k3s release like 1.27.4+k3s1 or one of {}",
+                version,
+                known.join(", ")
+            )
+        })
+}
+
+/// Port mappings are exposed through the k3d load balancer.
+fn k3d_ports(mappings: &[String]) -> Result<Vec<Port>> {
+    ports::parse_all(mappings)?
+        .into_iter()
+        .map(|pm| {
+            let protocol = match pm.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+                Protocol::Sctp => {
+                    return Err(anyhow!("The k3d provider does not support SCTP ports"))
+                }
+            };
+            let port = match &pm.listen_address {
+                Some(address) => format!(
+                    "{}:{}:{}/{}",
+                    address, pm.host_port, pm.container_port, protocol
+                ),
+                None => format!("{}:{}/{}", pm.host_port, pm.container_port, protocol),
+            };
+
+            Ok(Port {
+                port,
+                nodeFilters: vec![String::from("loadbalancer")],
+            })
+        })
+        .collect()
+}
+
+fn k3d_config(state: &ClusterState, registries: Registries) -> Result<SimpleConfig> {
+    let options = &state.options;
+
+    let registries = if registries.mirrors.is_empty() && registries.configs.is_empty() {
+        None
+    } else {
+        Some(RegistriesConfig {
+            config: serde_yaml::to_string(&registries)?,
+        })
+    };
+
+    Ok(SimpleConfig {
+        apiVersion: String::from("k3d.io/v1alpha5"),
+        kind: String::from("Simple"),
+        metadata: Metadata {
+            name: state.name.clone(),
+        },
+        servers: options.control_planes.unwrap_or(1),
+        agents: options.workers.unwrap_or(0),
+        image: options.k8s_version.as_deref().map(k3s_image).transpose()?,
+        ports: k3d_ports(&options.port_mappings)?,
+        registries,
+        options: Options {
+            kubeconfig: KubeconfigOptions {
+                updateDefaultKubeconfig: false,
+                switchCurrentContext: false,
+            },
+        },
+    })
+}
+
+/// Registry mirrors and credentials. ECR credentials are short lived, so
+/// they are fetched every time the cluster is created.
+fn registries(options: &CreateOptions) -> Result<Registries> {
+    let mut registries = Registries::default();
+
+    if let Some(container_name) = &options.local_registry {
+        // nodes reach the registry by name, see registry::attach
+        registry::check(container_name)?;
+        registries.mirrors.insert(
+            String::from(registry::HOST),
+            Mirror {
+                endpoint: vec![format!("http://{}:5000", container_name)],
+            },
+        );
+    }
+    if let Some(ecr) = &options.ecr {
+        let login = Kind::get_ecr_login(ecr).context("Could not get docker login")?;
+        registries.configs.insert(
+            ecr.clone(),
+            RegistryConfig {
+                auth: RegistryAuth {
+                    username: login.Username,
+                    password: login.Secret,
+                },
+            },
+        );
+    }
+
+    Ok(registries)
+}
+
+fn create_cluster(state: &ClusterState) -> Result<()> {
+    let config = k3d_config(state, registries(&state.options)?)?;
+    let config_path = format!("{}/k3d_config", state.dir());
+    // the config holds the ECR password, if any
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&config_path)?
+        .write_all(serde_yaml::to_string(&config)?.as_bytes())?;
+
+    let args = ["cluster", "create", "--config", &config_path];
+    let created = if state.options.verbose {
+        cmd::run_attached("k3d", &args)
+    } else {
+        cmd::run("k3d", &args).map(|_| ())
+    };
+    if state.options.ecr.is_some() {
+        fs::remove_file(&config_path)?;
+    }
+    created?;
+
+    let kubeconfig = format!("{}/kubeconfig", state.dir());
+    let contents = cmd::run("k3d", &["kubeconfig", "get", &state.name])?;
+    File::create(&kubeconfig)?.write_all(contents.as_bytes())?;
+
+    if let Some(container_name) = &state.options.local_registry {
+        let network = format!("k3d-{}", state.name);
+        registry::attach(container_name, &network, &kubeconfig)?;
+    }
+
+    provider::wait_until_ready(state, &kubeconfig)
+}
+
+fn list_clusters() -> Result<Vec<K3dCluster>> {
+    let output = cmd::run("k3d", &["cluster", "list", "-o", "json"])?;
+
+    Ok(serde_json::from_str(&output)?)
+}
+
+/// Version and number of nodes of a cluster, from its server image like
+/// `docker.io/rancher/k3s:v1.27.4-k3s1`.
+fn cluster_info(cluster: &K3dCluster) -> ClusterInfo {
+    let nodes: Vec<&K3dNode> = cluster
+        .nodes
+        .iter()
+        .filter(|n| n.role == "server" || n.role == "agent")
+        .collect();
+    let version = nodes
+        .iter()
+        .find(|n| n.role == "server")
+        .and_then(|n| n.image.rsplit(':').next())
+        .map(|tag| String::from(tag.split('-').next().unwrap_or(tag)));
+
+    ClusterInfo {
+        status: Status::Running,
+        version,
+        nodes: Some(nodes.len()),
+    }
+}
+
+pub struct K3d;
+
+impl Provider for K3d {
+    fn name(&self) -> &str {
+        "k3d"
+    }
+
+    fn validate(&self, _name: &str, options: &CreateOptions) -> Result<()> {
+        provider::reject_unsupported(self.name(), &provider::node_options(options))?;
+        if let Some(version) = &options.k8s_version {
+            k3s_image(version)?;
+        }
+        k3d_ports(&options.port_mappings)?;
+
+        Ok(())
+    }
+
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        // k3d reports busy ports with an obscure docker error
+        ports::check_available(&ports::parse_all(&state.options.port_mappings)?)?;
+
+        create_cluster(state)
+    }
+
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        cmd::run("k3d", &["cluster", "delete", &state.name])?;
+
+        Ok(())
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        Ok(self.info(state)?.status)
+    }
+
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        Ok(list_clusters()?
+            .iter()
+            .find(|c| c.name == state.name)
+            .map(cluster_info)
+            .unwrap_or_else(|| ClusterInfo::from(Status::NotFound)))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(list_clusters()?.into_iter().map(|c| c.name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd;
+    use crate::k3d::{self, K3d, K3dCluster, Registries};
+    use crate::provider::{CreateOptions, Provider, Status};
+    use crate::state::ClusterState;
+    use crate::testing::{self, Env, FakeRunner};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_k3d_config() {
+        let mut env = Env::new();
+        env.scratch_home("k3d-config");
+        let options = CreateOptions {
+            workers: Some(2),
+            port_mappings: vec![String::from("8080:80"), String::from("127.0.0.1:53:53/udp")],
+            k8s_version: Some(String::from("1.27.4")),
+            ..Default::default()
+        };
+        let state = ClusterState::new("tests", "k3d", options).unwrap();
+
+        let config = k3d::k3d_config(&state, Registries::default()).unwrap();
+        assert_eq!(config.metadata.name, "tests");
+        assert_eq!((config.servers, config.agents), (1, 2));
+        assert_eq!(config.image, Some(String::from("rancher/k3s:v1.27.4-k3s1")));
+        let ports: Vec<&str> = config.ports.iter().map(|p| &p.port[..]).collect();
+        assert_eq!(ports, vec!["8080:80/tcp", "127.0.0.1:53:53/udp"]);
+        assert!(config.registries.is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let options = CreateOptions {
+            node_labels: vec![String::from("worker:disk=ssd")],
+            node_taints: vec![String::from("worker:dedicated=db:NoSchedule")],
+            ..Default::default()
+        };
+        assert_eq!(
+            K3d.validate("tests", &options).err().unwrap().to_string(),
+            "The k3d provider does not support node labels, node taints"
+        );
+
+        let options = CreateOptions {
+            k8s_version: Some(String::from("1.19")),
+            ..Default::default()
+        };
+        assert_eq!(
+            K3d.validate("tests", &options).err().unwrap().to_string(),
+            "No k3s image known for Kubernetes 1.19, use a k3s release like 1.27.4+k3s1 or one of 1.24, 1.25, 1.26, 1.27"
+        );
+
+        let options = CreateOptions {
+            port_mappings: vec![String::from("9000/sctp")],
+            ..Default::default()
+        };
+        assert!(K3d.validate("tests", &options).is_err());
+    }
+
+    #[test]
+    fn test_k3s_image() {
+        assert_eq!(k3d::k3s_image("1.26").unwrap(), "rancher/k3s:v1.26.6-k3s1");
+        assert_eq!(
+            k3d::k3s_image("v1.27.4").unwrap(),
+            "rancher/k3s:v1.27.4-k3s1"
+        );
+        assert_eq!(
+            k3d::k3s_image("v1.25.16+k3s4").unwrap(),
+            "rancher/k3s:v1.25.16-k3s4"
+        );
+        assert_eq!(
+            k3d::k3s_image("1.27.5-k3s1").unwrap(),
+            "rancher/k3s:v1.27.5-k3s1"
+        );
+        assert!(k3d::k3s_image("1.27.5").is_err());
+    }
+
+    #[test]
+    fn test_cluster_info() {
+        let clusters: Vec<K3dCluster> = serde_json::from_str(
+            r#"[{"name": "tests", "nodes": [
+                {"name": "k3d-tests-server-0", "role": "server", "image": "docker.io/rancher/k3s:v1.27.4-k3s1"},
+                {"name": "k3d-tests-agent-0", "role": "agent", "image": "docker.io/rancher/k3s:v1.27.4-k3s1"},
+                {"name": "k3d-tests-serverlb", "role": "loadbalancer", "image": "ghcr.io/k3d-io/k3d-proxy:5.6.0"}
+            ]}]"#,
+        )
+        .unwrap();
+
+        let info = k3d::cluster_info(&clusters[0]);
+        assert_eq!(info.status, Status::Running);
+        assert_eq!(info.version, Some(String::from("v1.27.4")));
+        assert_eq!(info.nodes, Some(2));
+    }
+
+    #[test]
+    fn test_create() {
+        let mut env = Env::new();
+        let home = env.scratch_home("k3d-create");
+        let runner = Rc::new(FakeRunner::new().on("kubeconfig get", "apiVersion: v1"));
+
+        let options = CreateOptions {
+            no_wait: true,
+            ..Default::default()
+        };
+        cmd::with_runner(runner.clone(), || {
+            testing::cluster_flow("fast", "k3d", options, |state| {
+                assert_eq!(
+                    std::fs::read_to_string(format!("{}/kubeconfig", state.dir())).unwrap(),
+                    "apiVersion: v1"
+                );
+            })
+        });
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!("k3d cluster create --config {}/fast/k3d_config", home),
+                String::from("k3d kubeconfig get fast"),
+                String::from("k3d cluster delete fast"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_with_ecr() {
+        let mut env = Env::new();
+        let home = env.scratch_home("k3d-ecr");
+        let runner = Rc::new(
+            FakeRunner::new().on("ecr-login", r#"{"Username": "AWS", "Secret": "hunter2"}"#),
+        );
+
+        let options = CreateOptions {
+            ecr: Some(String::from("1234.dkr.ecr.eu-west-1.amazonaws.com")),
+            no_wait: true,
+            ..Default::default()
+        };
+        cmd::with_runner(runner, || crate::create("private", "k3d", options)).unwrap();
+
+        // the password isn't left behind
+        assert!(!std::path::Path::new(&format!("{}/private/k3d_config", home)).exists());
+    }
+
+    #[test]
+    fn test_create_with_local_registry() {
+        let mut env = Env::new();
+        let home = env.scratch_home("k3d-registry");
+        let runner = Rc::new(
+            FakeRunner::new()
+                .on(
+                    "container inspect",
+                    r#"[{"State": {"Status": "running"},
+                        "NetworkSettings": {"Networks": {"bridge": {}}}}]"#,
+                )
+                .on("network inspect", r#"[{"Name": "k3d-dev"}]"#),
+        );
+
+        let options = CreateOptions {
+            local_registry: Some(String::from("hake-registry")),
+            no_wait: true,
+            ..Default::default()
+        };
+        cmd::with_runner(runner.clone(), || crate::create("dev", "k3d", options)).unwrap();
+
+        let config = std::fs::read_to_string(format!("{}/dev/k3d_config", home)).unwrap();
+        assert!(config.contains("http://hake-registry:5000"));
+        let calls = runner.calls();
+        assert!(calls.contains(&String::from(
+            "docker network connect k3d-dev hake-registry"
+        )));
+        assert_eq!(
+            calls.last().unwrap(),
+            &format!("kubectl --kubeconfig {}/dev/kubeconfig apply -f -", home)
+        );
+    }
+}
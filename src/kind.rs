@@ -1,16 +1,13 @@
 #![allow(non_snake_case)]
 
-use anyhow::Result;
-use dirs;
+use anyhow::{anyhow, Context, Result};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
 use base64::encode;
-use std::collections::HashMap;
-use std::fs::{create_dir, remove_dir_all, File};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
 use std::str;
 use std::vec::Vec;
 
@@ -20,6 +17,26 @@ use tokio::runtime::Runtime;
 
 use regex::Regex;
 
+use crate::cmd;
+use crate::ports;
+use crate::provider::{self, ClusterInfo, Provider, Status};
+use crate::registry;
+use crate::state::ClusterState;
+
+const KIND_CLUSTER_LABEL: &str = "io.x-k8s.kind.cluster";
+
+/// Node images for each Kubernetes minor version, as published with the kind
+/// v0.20.0 release. More versions can be added in ~/.hake/node-images.yaml.
+const NODE_IMAGES: &[(&str, &str)] = &[
+    ("1.21", "kindest/node:v1.21.14@sha256:8a4e9bb3f415d2bb81629ce33ef9c76ba514c14d707f9797a01e3216376ba093"),
+    ("1.22", "kindest/node:v1.22.17@sha256:f5b2e5698c6c9d6d0adc419c0deae21a425c07d81bbf3b6a6834042f25d4fba2"),
+    ("1.23", "kindest/node:v1.23.17@sha256:59c989ff8a517a93127d4a536e7014d28e235fb3529d9fba91b3951d461edfdb"),
+    ("1.24", "kindest/node:v1.24.15@sha256:7db4f8bea3e14b82d12e044e25e34bd53754b7f2b0e9d56df21774e6f66a70ab"),
+    ("1.25", "kindest/node:v1.25.11@sha256:227fa11ce74ea76a0474eeefb84cb75d8dad1b08638371ecf0e86259b35be0c8"),
+    ("1.26", "kindest/node:v1.26.6@sha256:6e2d8b28a5b601defe327b98bd1c2d1930b49e5d8c512e1895099e4f4ea7d6c1"),
+    ("1.27", "kindest/node:v1.27.3@sha256:3966ac761ae0136263ffdb6cfd4db23ef8a83cba8a463690e98317add2c9ba72"),
+];
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ExtraMount {
     containerPath: String,
@@ -28,6 +45,8 @@ struct ExtraMount {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct PortMapping {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listenAddress: Option<String>,
     containerPort: u32,
     hostPort: u32,
     protocol: String,
@@ -36,11 +55,34 @@ struct PortMapping {
 #[derive(Serialize, Deserialize, Debug)]
 struct Node {
     role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
     extraMounts: Vec<ExtraMount>,
     extraPortMappings: Vec<PortMapping>,
     kubeadmConfigPatches: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Taint {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    effect: String,
+}
+
+#[derive(Serialize, Debug)]
+struct NodeRegistration {
+    kubeletExtraArgs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    taints: Vec<Taint>,
+}
+
+#[derive(Serialize, Debug)]
+struct KubeadmConfigPatch {
+    kind: String,
+    nodeRegistration: NodeRegistration,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ClusterConfig {
     kind: String,
@@ -50,9 +92,9 @@ struct ClusterConfig {
 }
 
 #[derive(Deserialize, Debug)]
-struct DockerLogin {
-    Username: String,
-    Secret: String,
+pub(crate) struct DockerLogin {
+    pub Username: String,
+    pub Secret: String,
 }
 
 pub struct Kind {
@@ -60,7 +102,13 @@ pub struct Kind {
     pub ecr_repo: Option<String>,
     config_dir: String,
     local_registry: Option<String>,
-    extra_port_mapping: Option<String>,
+    port_mappings: Vec<String>,
+    control_planes: u32,
+    workers: u32,
+    node_labels: Vec<String>,
+    node_taints: Vec<String>,
+    k8s_version: Option<String>,
+    node_images: Vec<String>,
     verbose: bool,
 }
 
@@ -81,26 +129,104 @@ impl Kind {
     fn kind_node(role: &str, container_path: Option<&str>, host_path: Option<&str>) -> Node {
         Node {
             role: String::from(role),
+            image: None,
             extraMounts: Kind::extra_mount(container_path, host_path),
             extraPortMappings: vec![],
             kubeadmConfigPatches: vec![],
         }
     }
 
-    fn init_config_ingress_ready() -> String {
-        String::from(
-            r#"kind: InitConfiguration
-nodeRegistration:
-  kubeletExtraArgs:
-    node-labels: "ingress-ready=true""#,
-        )
+    /// Builds the kubeadm patch that registers a node with `labels` and
+    /// `taints`. The first control-plane is configured with an
+    /// InitConfiguration, every other node joins the cluster.
+    fn kubeadm_config_patch(kind: &str, labels: &[String], taints: Vec<Taint>) -> Result<String> {
+        let mut kubelet_extra_args = BTreeMap::new();
+        if !labels.is_empty() {
+            kubelet_extra_args.insert(String::from("node-labels"), labels.join(","));
+        }
+
+        let patch = serde_yaml::to_string(&KubeadmConfigPatch {
+            kind: String::from(kind),
+            nodeRegistration: NodeRegistration {
+                kubeletExtraArgs: kubelet_extra_args,
+                taints,
+            },
+        })?;
+
+        Ok(String::from(patch.trim_start_matches("---\n").trim_end()))
+    }
+
+    /// Splits a node option like `worker-2:disk=ssd` into its node selector
+    /// and value. Selectors are `all`, a role (`control-plane` or `worker`) or
+    /// a role followed by the 1-based index of the node in that role.
+    fn parse_node_option<'a>(&self, option: &'a str) -> Result<(&'a str, &'a str)> {
+        let mut parts = option.splitn(2, ':');
+        let selector = parts.next().unwrap_or("");
+        let value = parts
+            .next()
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| anyhow!("Invalid node option {}, expected <node>:<value>", option))?;
+
+        let re = Regex::new(r"^(control-plane|worker)(-(\d+))?$").unwrap();
+        let valid = match re.captures(selector) {
+            _ if selector == "all" => true,
+            Some(cap) => {
+                let count = if &cap[1] == "worker" {
+                    self.workers
+                } else {
+                    self.control_planes
+                };
+                match cap.get(3) {
+                    Some(index) => index
+                        .as_str()
+                        .parse::<u32>()
+                        .map(|i| i >= 1 && i <= count)
+                        .unwrap_or(false),
+                    None => count > 0,
+                }
+            }
+            None => false,
+        };
+        if !valid {
+            return Err(anyhow!(
+                "Invalid node {} in {}: the cluster has {} control-plane and {} worker nodes",
+                selector,
+                option,
+                self.control_planes,
+                self.workers
+            ));
+        }
+
+        Ok((selector, value))
+    }
+
+    fn selects(selector: &str, role: &str, index: u32) -> bool {
+        selector == "all" || selector == role || selector == format!("{}-{}", role, index)
+    }
+
+    /// receives a string like: dedicated=db:NoSchedule or dedicated:NoExecute
+    fn parse_taint(taint: &str) -> Result<Taint> {
+        let re =
+            Regex::new(r"^([^=:]+)(=([^=:]*))?:(NoSchedule|PreferNoSchedule|NoExecute)$").unwrap();
+        let cap = re.captures(taint).ok_or_else(|| {
+            anyhow!(
+                "Invalid taint {}, expected key[=value]:NoSchedule|PreferNoSchedule|NoExecute",
+                taint
+            )
+        })?;
+
+        Ok(Taint {
+            key: String::from(&cap[1]),
+            value: cap.get(3).map(|v| String::from(v.as_str())),
+            effect: String::from(&cap[4]),
+        })
     }
 
     fn get_kind_cluster_config(
         &self,
         ecr: &Option<String>,
         local_reg: &Option<String>,
-    ) -> ClusterConfig {
+    ) -> Result<ClusterConfig> {
         let mut cc = ClusterConfig {
             kind: String::from("Cluster"),
             apiVersion: String::from("kind.x-k8s.io/v1alpha4"),
@@ -108,14 +234,97 @@ nodeRegistration:
             containerdConfigPatches: vec![],
         };
 
+        if self.control_planes == 0 {
+            return Err(anyhow!("A cluster needs at least one control-plane node"));
+        }
+
+        let mut labels = vec![];
+        for label in &self.node_labels {
+            labels.push(self.parse_node_option(label)?);
+        }
+        let mut taints = vec![];
+        for taint in &self.node_taints {
+            let (selector, taint) = self.parse_node_option(taint)?;
+            taints.push((selector, Kind::parse_taint(taint)?));
+        }
+
+        let mut default_image = None;
+        let mut images = vec![];
+        if self.k8s_version.is_some() || !self.node_images.is_empty() {
+            let known_images = Kind::get_known_node_images()?;
+            if let Some(version) = &self.k8s_version {
+                default_image = Some(Kind::resolve_node_image(&known_images, version)?);
+            }
+            for image in &self.node_images {
+                let (selector, image) = self.parse_node_option(image)?;
+                images.push((selector, Kind::node_image(&known_images, image)?));
+            }
+        }
+
+        // every kubelet needs the credentials to pull from ECR
+        let mut docker_path = None;
         if let Some(ecr) = ecr {
-            if let Ok(docker_path) = self.create_docker_ecr_config_file(ecr) {
-                cc.nodes = vec![Kind::kind_node(
-                    "control-plane",
-                    Some("/var/lib/kubelet/config.json"),
-                    Some(&docker_path),
-                )];
+            docker_path = Some(self.create_docker_ecr_config_file(ecr)?);
+        }
+
+        let mut port_mappings: Vec<PortMapping> = ports::parse_all(&self.port_mappings)?
+            .into_iter()
+            .map(|pm| PortMapping {
+                listenAddress: pm.listen_address,
+                containerPort: u32::from(pm.container_port),
+                hostPort: u32::from(pm.host_port),
+                protocol: String::from(pm.protocol.as_str()),
+            })
+            .collect();
+
+        let roles = (1..=self.control_planes)
+            .map(|i| ("control-plane", i))
+            .chain((1..=self.workers).map(|i| ("worker", i)));
+        for (role, index) in roles {
+            let mut node = Kind::kind_node(
+                role,
+                docker_path.as_ref().map(|_| "/var/lib/kubelet/config.json"),
+                docker_path.as_deref(),
+            );
+
+            // the last image that selects this node wins
+            node.image = images
+                .iter()
+                .rev()
+                .find(|(selector, _)| Kind::selects(selector, role, index))
+                .map(|(_, image)| image.clone())
+                .or_else(|| default_image.clone());
+
+            let mut node_labels: Vec<String> = labels
+                .iter()
+                .filter(|(selector, _)| Kind::selects(selector, role, index))
+                .map(|(_, label)| String::from(*label))
+                .collect();
+            let node_taints: Vec<Taint> = taints
+                .iter()
+                .filter(|(selector, _)| Kind::selects(selector, role, index))
+                .map(|(_, taint)| taint.clone())
+                .collect();
+
+            let first = role == "control-plane" && index == 1;
+            if first {
+                // ports are exposed by the first control-plane, where the
+                // kind manifests of ingress controllers are scheduled
+                node.extraPortMappings = std::mem::take(&mut port_mappings);
+                node_labels.insert(0, String::from("ingress-ready=true"));
             }
+
+            if !node_labels.is_empty() || !node_taints.is_empty() {
+                let kind = if first {
+                    "InitConfiguration"
+                } else {
+                    "JoinConfiguration"
+                };
+                node.kubeadmConfigPatches =
+                    vec![Kind::kubeadm_config_patch(kind, &node_labels, node_taints)?];
+            }
+
+            cc.nodes.push(node);
         }
 
         if let Some(local_reg) = local_reg {
@@ -124,15 +333,18 @@ nodeRegistration:
             )];
         }
 
-        cc
+        Ok(cc)
     }
 
-    fn get_containerd_config_patch_to_local_registry(ip: &str) -> String {
+    /// Nodes reach the registry container by name, through the kind
+    /// network.
+    fn get_containerd_config_patch_to_local_registry(container_name: &str) -> String {
         format!(
             r#"
-[plugins."io.containerd.grpc.v1.cri".registry.mirrors."localhost:5000"]
+[plugins."io.containerd.grpc.v1.cri".registry.mirrors."{}"]
   endpoint = ["http://{}:5000"]"#,
-            ip.trim()
+            registry::HOST,
+            container_name.trim()
         )
     }
 
@@ -181,10 +393,52 @@ nodeRegistration:
         rt.block_on(Kind::async_get_containers())
     }
 
-    fn get_docker_login(registry: &str) -> Result<String> {
+    /// Returns the images of the running node containers of cluster `name`.
+    async fn async_get_node_images(name: &str) -> Result<Vec<String>> {
+        let docker = Docker::connect_with_local_defaults()?;
+        let mut filter = HashMap::new();
+        filter.insert(String::from("status"), vec![String::from("running")]);
+        filter.insert(
+            String::from("label"),
+            vec![format!("{}={}", KIND_CLUSTER_LABEL, name)],
+        );
+        let containers = docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters: filter,
+                ..Default::default()
+            }))
+            .await?;
+
+        Ok(containers
+            .into_iter()
+            .filter(|c| c.image.starts_with("kindest/node"))
+            .map(|c| c.image)
+            .collect())
+    }
+
+    pub fn get_node_images(name: &str) -> Result<Vec<String>> {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(Kind::async_get_node_images(name))
+    }
+
+    /// Gets the Kubernetes version from a node image like
+    /// `kindest/node:v1.18.2@sha256:...`.
+    fn get_image_version(image: &str) -> Option<String> {
+        let tag = image.split('@').next()?.split(':').nth(1)?;
+        Some(String::from(tag))
+    }
+
+    /// Gets short lived credentials for an ECR registry from the ECR login
+    /// helper.
+    pub(crate) fn get_ecr_login(registry: &str) -> Result<DockerLogin> {
         let creds = Kind::get_docker_credentials_from_helper(registry)?;
 
-        let login: DockerLogin = serde_json::from_str(&creds)?;
+        Ok(serde_json::from_str(&creds)?)
+    }
+
+    fn get_docker_login(registry: &str) -> Result<String> {
+        let login = Kind::get_ecr_login(registry)?;
         let encoded = encode(&format!("{}:{}", login.Username, login.Secret));
 
         Ok(json!({
@@ -199,26 +453,11 @@ nodeRegistration:
     }
 
     fn get_docker_credentials_from_helper(registry: &str) -> Result<String> {
-        let mut cmd = Command::new("docker-credential-ecr-login")
-            .arg("get")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap_or_else(|_| {
-                panic!("Could not find docker credentials helper for {}", registry)
-            });
-
-        cmd.stdin.as_mut().unwrap().write_all(registry.as_bytes())?;
-        cmd.wait()?;
-
-        let mut output = String::new();
-        cmd.stdout.unwrap().read_to_string(&mut output)?;
-
-        Ok(output)
+        cmd::run_with_input("docker-credential-ecr-login", &["get"], registry)
     }
 
     fn create_docker_ecr_config_file(&self, ecr: &str) -> Result<String> {
-        let docker_login = Kind::get_docker_login(ecr).expect("could not get docker login");
+        let docker_login = Kind::get_docker_login(ecr).context("Could not get docker login")?;
 
         // save docker_login()
         let docker_config_path = format!("{}/docker_config", self.config_dir);
@@ -228,26 +467,8 @@ nodeRegistration:
         Ok(docker_config_path)
     }
 
-    fn create_dirs(cluster_name: &str) -> Result<()> {
-        let home = Kind::get_config_dir()?;
-
-        if !Path::new(&home).exists() {
-            create_dir(&home)?;
-        }
-        create_dir(format!("{}/{}", &home, cluster_name))?;
-
-        Ok(())
-    }
-
     pub fn get_config_dir() -> Result<String> {
-        let home = String::from(
-            dirs::home_dir()
-                .expect("User does not have a home")
-                .to_str()
-                .unwrap(),
-        );
-
-        Ok(format!("{}/.hake", home))
+        crate::get_config_dir()
     }
 
     pub fn configure_private_registry(&mut self, reg: Option<String>) {
@@ -258,92 +479,111 @@ nodeRegistration:
         self.verbose = verbose;
     }
 
-    fn find_local_registry(container_name: &str) -> Option<String> {
-        let ip = Command::new("docker")
-            .arg("inspect")
-            .arg("-f")
-            .arg("{{.NetworkSettings.IPAddress}}")
-            .arg(container_name)
-            .output()
-            .expect(&format!(
-                "Could not get IP from {} container",
-                container_name
-            ));
+    pub fn use_local_registry(&mut self, container_name: &str) -> Result<()> {
+        registry::check(container_name)?;
+        self.local_registry = Some(String::from(container_name));
+
+        Ok(())
+    }
+
+    /// Port mappings like `8080:80/tcp`, see `ports::parse`.
+    pub fn port_mappings(&mut self, port_mappings: Vec<String>) {
+        self.port_mappings = port_mappings;
+    }
 
-        Some(String::from_utf8(ip.stdout).unwrap().trim().to_string())
+    pub fn nodes(&mut self, control_planes: u32, workers: u32) {
+        self.control_planes = control_planes;
+        self.workers = workers;
     }
 
-    pub fn use_local_registry(&mut self, container_name: &str) {
-        self.local_registry = Kind::find_local_registry(container_name);
+    /// Labels like `worker:disk=ssd`, see `parse_node_option`.
+    pub fn node_labels(&mut self, labels: Vec<String>) {
+        self.node_labels = labels;
     }
 
-    pub fn extra_port_mapping(&mut self, extra_port_mapping: &str) {
-        self.extra_port_mapping = Some(String::from(extra_port_mapping));
+    /// Taints like `worker-1:dedicated=db:NoSchedule`, see `parse_node_option`.
+    pub fn node_taints(&mut self, taints: Vec<String>) {
+        self.node_taints = taints;
     }
 
-    /// receives a string like: 80:80:TCP or 80:80 or 80
-    fn parse_extra_port_mappings(epm: &str) -> Option<PortMapping> {
-        let mut container_port = 0;
-        let mut host_port = 0;
-        let mut proto = String::from("TCP");
+    /// Kubernetes version of every node, like `1.27`.
+    pub fn k8s_version(&mut self, version: Option<String>) {
+        self.k8s_version = version;
+    }
 
-        let re0 = Regex::new(r"^(\d{2}):(\d{2}):(TCP|HTTP)$").unwrap();
-        let re1 = Regex::new(r"^(\d{2}):(\d{2})$").unwrap();
-        let re2 = Regex::new(r"^(\d+)$").unwrap();
+    /// Images like `worker-2:kindest/node:v1.26.6` or `worker-2:1.26`, see
+    /// `parse_node_option`.
+    pub fn node_images(&mut self, images: Vec<String>) {
+        self.node_images = images;
+    }
 
-        if re0.is_match(epm) {
-            let cap = re0.captures(epm).unwrap();
-            container_port = cap[1].parse::<u32>().unwrap();
-            host_port = cap[2].parse::<u32>().unwrap();
-            proto = String::from(&cap[3]);
-        } else if let Some(cap) = re1.captures(epm) {
-            container_port = cap[1].parse::<u32>().unwrap();
-            host_port = cap[2].parse::<u32>().unwrap();
-        } else if let Some(cap) = re2.captures(epm) {
-            container_port = cap[1].parse::<u32>().unwrap();
-            host_port = container_port;
+    /// Returns the built-in node images along with the ones configured in
+    /// ~/.hake/node-images.yaml, a map of Kubernetes version to image.
+    fn get_known_node_images() -> Result<BTreeMap<String, String>> {
+        let mut images: BTreeMap<String, String> = NODE_IMAGES
+            .iter()
+            .map(|(v, i)| (String::from(*v), String::from(*i)))
+            .collect();
+
+        let images_file = format!("{}/node-images.yaml", Kind::get_config_dir()?);
+        if let Ok(mut file) = File::open(&images_file) {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            let extra: BTreeMap<String, String> = serde_yaml::from_str(&contents)
+                .with_context(|| format!("Could not parse {}", images_file))?;
+            images.extend(extra);
         }
 
-        if container_port != 0 {
-            Some(PortMapping {
-                containerPort: container_port,
-                hostPort: host_port,
-                protocol: proto,
+        Ok(images)
+    }
+
+    /// Resolves a version like `1.27` or `v1.27.3` into a node image.
+    fn resolve_node_image(images: &BTreeMap<String, String>, version: &str) -> Result<String> {
+        let version = version.trim_start_matches('v');
+        if let Some(image) = images.get(version) {
+            return Ok(image.clone());
+        }
+
+        let tag = format!("v{}", version);
+        images
+            .values()
+            .find(|image| Kind::get_image_version(image).as_ref() == Some(&tag))
+            .cloned()
+            .ok_or_else(|| {
+                let known: Vec<&str> = images.keys().map(|k| &k[..]).collect();
+                anyhow!(
+                    "Unknown Kubernetes version {}. Known versions are: {}",
+                    version,
+                    known.join(", ")
+                )
             })
+    }
+
+    /// A node image option is either a version or an image.
+    fn node_image(images: &BTreeMap<String, String>, image: &str) -> Result<String> {
+        let re = Regex::new(r"^v?\d+\.\d+(\.\d+)?$").unwrap();
+        if re.is_match(image) {
+            Kind::resolve_node_image(images, image)
         } else {
-            None
+            Ok(String::from(image))
         }
     }
 
     pub fn create(self) -> Result<()> {
-        Kind::create_dirs(&self.name)?;
+        // kind reports busy ports with an obscure docker error
+        ports::check_available(&ports::parse_all(&self.port_mappings)?)?;
 
         let mut args = vec!["create", "cluster"];
-        let kubeconfig;
+        let kubeconfig = format!("{}/kubeconfig", self.config_dir);
 
         args.push("--name");
         args.push(&self.name);
 
-        kubeconfig = format!("{}/kubeconfig", self.config_dir);
         args.push("--kubeconfig");
         args.push(&kubeconfig);
 
         args.push("--config");
-        let mut kind_config = self.get_kind_cluster_config(&self.ecr_repo, &self.local_registry);
-        if let Some(extra_port_mapping) = self.extra_port_mapping {
-            let epm = Kind::parse_extra_port_mappings(&extra_port_mapping);
-            if let Some(epm) = epm {
-                if let Some(mut node) = kind_config.nodes.get_mut(0) {
-                    node.extraPortMappings = vec![epm];
-                } else {
-                    let mut nn = Kind::kind_node("control-plane", None, None);
-                    nn.extraPortMappings = vec![epm];
-                    kind_config.nodes = vec![nn];
-                };
-                kind_config.nodes[0].kubeadmConfigPatches = vec![Kind::init_config_ingress_ready()];
-            }
-        }
-
+        let kind_config = self.get_kind_cluster_config(&self.ecr_repo, &self.local_registry)?;
         let kind_cluster_config = serde_yaml::to_string(&kind_config)?;
 
         let kind_config_path = format!("{}/kind_config", self.config_dir);
@@ -355,48 +595,19 @@ nodeRegistration:
 
         Kind::run(&args, self.verbose)?;
 
-        let config_dir = Kind::get_config_dir()?;
-        let config_dir = format!("{}/{}/kind_args", config_dir, &self.name);
-        let mut saved_args = File::create(config_dir)?;
-        saved_args.write_all(args.join(" ").as_bytes())?;
+        if let Some(container_name) = &self.local_registry {
+            registry::attach(container_name, registry::KIND_NETWORK, &kubeconfig)?;
+        }
 
         Ok(())
     }
 
-    pub fn run(args: &Vec<&str>, verbose: bool) -> Result<()> {
-        let mut command = Command::new("kind");
-        command.args(args);
+    pub fn run(args: &[&str], verbose: bool) -> Result<()> {
         if verbose {
-            command.spawn()?.wait()?;
+            cmd::run_attached("kind", args)
         } else {
-            command.output()?;
+            cmd::run("kind", args).map(|_| ())
         }
-
-        Ok(())
-    }
-
-    pub fn recreate(name: &str, verbose: bool) -> Result<()> {
-        let config_dir = format!("{}/{}", Kind::get_config_dir()?, name);
-        let args_file = format!("{}/kind_args", config_dir);
-
-        let mut contents = String::new();
-        let mut saved_args = File::open(args_file)?;
-        saved_args.read_to_string(&mut contents)?;
-
-        Kind::delete_cluster(name)?;
-
-        let args: Vec<&str> = contents.split_ascii_whitespace().collect();
-        Kind::run(&args, verbose)?;
-
-        Ok(())
-    }
-
-    pub fn delete(&self) -> Result<()> {
-        Kind::delete_cluster(&self.name)?;
-
-        remove_dir_all(&self.config_dir)?;
-
-        Ok(())
     }
 
     fn delete_cluster(name: &str) -> Result<()> {
@@ -404,37 +615,117 @@ nodeRegistration:
         args.push("--name");
         args.push(name);
 
-        let _cmd = Command::new("kind").args(args).output()?;
+        cmd::run("kind", &args)?;
 
         Ok(())
     }
 
-    pub fn new(name: &str) -> Kind {
-        let config = Kind::get_config_dir();
-        if config.is_err() {
-            panic!("User has no home!!");
-        }
-        let home = config.unwrap();
+    pub fn new(name: &str) -> Result<Kind> {
+        let home = Kind::get_config_dir()?;
 
-        Kind {
+        Ok(Kind {
             name: String::from(name),
             ecr_repo: None,
             config_dir: format!("{}/{}", home, name),
             local_registry: None,
-            extra_port_mapping: None,
+            port_mappings: vec![],
+            control_planes: 1,
+            workers: 0,
+            node_labels: vec![],
+            node_taints: vec![],
+            k8s_version: None,
+            node_images: vec![],
             verbose: false,
+        })
+    }
+}
+
+pub struct KindProvider;
+
+impl Provider for KindProvider {
+    fn name(&self) -> &str {
+        "kind"
+    }
+
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        let options = &state.options;
+        let mut cluster = Kind::new(&state.name)?;
+        cluster.configure_private_registry(options.ecr.clone());
+
+        if let Some(container_name) = &options.local_registry {
+            cluster.use_local_registry(container_name)?;
+        }
+        cluster.port_mappings(options.port_mappings.clone());
+        cluster.nodes(
+            options.control_planes.unwrap_or(1),
+            options.workers.unwrap_or(0),
+        );
+        cluster.node_labels(options.node_labels.clone());
+        cluster.node_taints(options.node_taints.clone());
+        cluster.k8s_version(options.k8s_version.clone());
+        cluster.node_images(options.node_images.clone());
+        cluster.set_verbose(options.verbose);
+        cluster.create()?;
+
+        provider::wait_until_ready(state, &self.kubeconfig(state)?)
+    }
+
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        Kind::delete_cluster(&state.name)
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        if self.list()?.contains(&state.name) {
+            Ok(Status::Running)
+        } else {
+            Ok(Status::NotFound)
+        }
+    }
+
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        let images =
+            Kind::get_node_images(&state.name).context("Could not list docker containers")?;
+        if images.is_empty() {
+            return Ok(ClusterInfo::from(Status::NotFound));
         }
+
+        Ok(ClusterInfo {
+            status: Status::Running,
+            version: images.iter().find_map(|i| Kind::get_image_version(i)),
+            nodes: Some(images.len()),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Kind::get_kind_containers()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::kind::Kind;
+    use crate::kind::{Kind, Node, Taint};
+    use crate::testing::Env;
+    use std::collections::BTreeMap;
+
+    /// The kubeadm patches of `node`, parsed so they compare the same with
+    /// any serde_yaml version.
+    fn patches(node: &Node) -> Vec<serde_yaml::Value> {
+        node.kubeadmConfigPatches
+            .iter()
+            .map(|patch| serde_yaml::from_str(patch).unwrap())
+            .collect()
+    }
+
+    fn yaml(patch: &str) -> Vec<serde_yaml::Value> {
+        vec![serde_yaml::from_str(patch).unwrap()]
+    }
 
     #[test]
     fn test_new() {
         // TODO: test configuration on home directory.
-        let k = Kind::new("test");
+        let mut env = Env::new();
+        env.remove("HAKE_HOME");
+        let k = Kind::new("test").unwrap();
 
         let home = dirs::home_dir().unwrap();
 
@@ -459,4 +750,211 @@ mod tests {
             Some(String::from("this-is-us"))
         );
     }
+
+    #[test]
+    fn test_get_kind_cluster_config() {
+        let mut env = Env::new();
+        env.scratch_home("kind-config");
+        let mut k = Kind::new("test").unwrap();
+        k.nodes(3, 2);
+        k.port_mappings(vec![String::from("80:80"), String::from("53:53/udp")]);
+        k.node_labels(vec![
+            String::from("worker:disk=ssd"),
+            String::from("worker-2:zone=b"),
+        ]);
+        k.node_taints(vec![String::from("worker-2:dedicated=db:NoSchedule")]);
+
+        let cc = k.get_kind_cluster_config(&None, &None).unwrap();
+        let roles: Vec<&str> = cc.nodes.iter().map(|n| &n.role[..]).collect();
+        assert_eq!(
+            roles,
+            vec![
+                "control-plane",
+                "control-plane",
+                "control-plane",
+                "worker",
+                "worker"
+            ]
+        );
+
+        assert_eq!(cc.nodes[0].extraPortMappings.len(), 2);
+        assert_eq!(
+            patches(&cc.nodes[0]),
+            yaml(
+                "kind: InitConfiguration
+nodeRegistration:
+  kubeletExtraArgs:
+    node-labels: ingress-ready=true"
+            )
+        );
+        assert!(cc.nodes[1].extraPortMappings.is_empty());
+        assert!(cc.nodes[1].kubeadmConfigPatches.is_empty());
+        assert_eq!(
+            patches(&cc.nodes[3]),
+            yaml(
+                "kind: JoinConfiguration
+nodeRegistration:
+  kubeletExtraArgs:
+    node-labels: disk=ssd"
+            )
+        );
+        assert_eq!(
+            patches(&cc.nodes[4]),
+            yaml(
+                "kind: JoinConfiguration
+nodeRegistration:
+  kubeletExtraArgs:
+    node-labels: disk=ssd,zone=b
+  taints:
+    - key: dedicated
+      value: db
+      effect: NoSchedule"
+            )
+        );
+
+        // ingress controllers can be installed without ports too
+        k.port_mappings(vec![]);
+        let cc = k.get_kind_cluster_config(&None, &None).unwrap();
+        assert!(cc.nodes[0].extraPortMappings.is_empty());
+        assert_eq!(
+            patches(&cc.nodes[0]),
+            yaml(
+                "kind: InitConfiguration
+nodeRegistration:
+  kubeletExtraArgs:
+    node-labels: ingress-ready=true"
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_kind_cluster_config_errors() {
+        let mut env = Env::new();
+        env.scratch_home("kind-config-errors");
+        let mut k = Kind::new("test").unwrap();
+        k.nodes(1, 2);
+
+        k.node_labels(vec![String::from("worker-3:disk=ssd")]);
+        assert_eq!(
+            k.get_kind_cluster_config(&None, &None).err().unwrap().to_string(),
+            "Invalid node worker-3 in worker-3:disk=ssd: the cluster has 1 control-plane and 2 worker nodes"
+        );
+
+        k.node_labels(vec![String::from("worker")]);
+        assert_eq!(
+            k.get_kind_cluster_config(&None, &None)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Invalid node option worker, expected <node>:<value>"
+        );
+
+        k.node_labels(vec![]);
+        k.node_taints(vec![String::from("all:dedicated=db")]);
+        assert!(k.get_kind_cluster_config(&None, &None).is_err());
+
+        k.nodes(0, 2);
+        assert!(k.get_kind_cluster_config(&None, &None).is_err());
+    }
+
+    #[test]
+    fn test_get_kind_cluster_config_images() {
+        let mut env = Env::new();
+        let home = env.scratch_home("kind-config-images");
+        let mut k = Kind::new("test").unwrap();
+        k.nodes(1, 2);
+        k.k8s_version(Some(String::from("1.26")));
+        k.node_images(vec![
+            String::from("worker:1.25"),
+            String::from("worker-2:kindest/node:v1.27.1"),
+        ]);
+
+        let cc = k.get_kind_cluster_config(&None, &None).unwrap();
+        let images: Vec<&str> = cc
+            .nodes
+            .iter()
+            .map(|n| n.image.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            images,
+            vec![
+                "kindest/node:v1.26.6@sha256:6e2d8b28a5b601defe327b98bd1c2d1930b49e5d8c512e1895099e4f4ea7d6c1",
+                "kindest/node:v1.25.11@sha256:227fa11ce74ea76a0474eeefb84cb75d8dad1b08638371ecf0e86259b35be0c8",
+                "kindest/node:v1.27.1",
+            ]
+        );
+
+        // versions in ~/.hake/node-images.yaml are known too
+        std::fs::write(
+            format!("{}/node-images.yaml", home),
+            "\"1.29\": kindest/node:v1.29.0@sha256:eaa1450",
+        )
+        .unwrap();
+        k.k8s_version(Some(String::from("1.29")));
+        k.node_images(vec![]);
+        let cc = k.get_kind_cluster_config(&None, &None).unwrap();
+        assert_eq!(
+            cc.nodes[0].image.as_deref(),
+            Some("kindest/node:v1.29.0@sha256:eaa1450")
+        );
+    }
+
+    #[test]
+    fn test_resolve_node_image() {
+        let mut images = BTreeMap::new();
+        images.insert(
+            String::from("1.27"),
+            String::from("kindest/node:v1.27.3@sha256:3966ac"),
+        );
+
+        assert_eq!(
+            Kind::resolve_node_image(&images, "1.27").unwrap(),
+            "kindest/node:v1.27.3@sha256:3966ac"
+        );
+        assert_eq!(
+            Kind::resolve_node_image(&images, "v1.27.3").unwrap(),
+            "kindest/node:v1.27.3@sha256:3966ac"
+        );
+        assert_eq!(
+            Kind::resolve_node_image(&images, "1.27.1")
+                .err()
+                .unwrap()
+                .to_string(),
+            "Unknown Kubernetes version 1.27.1. Known versions are: 1.27"
+        );
+    }
+
+    #[test]
+    fn test_parse_taint() {
+        assert_eq!(
+            Kind::parse_taint("dedicated=db:NoSchedule").unwrap(),
+            Taint {
+                key: String::from("dedicated"),
+                value: Some(String::from("db")),
+                effect: String::from("NoSchedule"),
+            }
+        );
+        assert_eq!(
+            Kind::parse_taint("spot:PreferNoSchedule").unwrap(),
+            Taint {
+                key: String::from("spot"),
+                value: None,
+                effect: String::from("PreferNoSchedule"),
+            }
+        );
+        assert!(Kind::parse_taint("spot:Never").is_err());
+    }
+
+    #[test]
+    fn test_get_image_version() {
+        assert_eq!(
+            Kind::get_image_version("kindest/node:v1.18.2"),
+            Some(String::from("v1.18.2"))
+        );
+        assert_eq!(
+            Kind::get_image_version("kindest/node:v1.18.2@sha256:7b27a6d0f2517ff88ba444025beae41491b016bc6af573ba467b70c5e8e0d85f"),
+            Some(String::from("v1.18.2"))
+        );
+        assert_eq!(Kind::get_image_version("kindest/node"), None);
+    }
 }
@@ -0,0 +1,665 @@
+//! hake creates Kubernetes clusters for testing, locally with kind or
+//! remotely on DigitalOcean, and keeps track of them under `~/.hake`.
+//!
+//! ```no_run
+//! use hake::provider::CreateOptions;
+//!
+//! let options = CreateOptions {
+//!     workers: Some(2),
+//!     ..Default::default()
+//! };
+//! let state = hake::create("operator-tests", "kind", options)?;
+//! println!("export KUBECONFIG={}", hake::kubeconfig(&state.name)?);
+//!
+//! hake::delete(&state.name)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Nothing here prints to stdout, progress is reported through the `log`
+//! crate at the info level.
+
+use anyhow::{anyhow, Result};
+
+pub mod addons;
+mod cmd;
+mod r#do;
+mod k3d;
+mod kind;
+mod linode;
+pub mod list;
+pub mod matrix;
+mod minikube;
+mod plugin;
+pub mod ports;
+pub mod provider;
+mod ready;
+pub mod registry;
+mod rest;
+pub mod spec;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod wait;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::vec::Vec;
+
+use crate::addons::AddOptions;
+use crate::provider::{CreateOptions, Status};
+use crate::state::{ClusterState, InstalledAddon};
+
+/// Creates cluster `name` with `provider`. The cluster directory is removed
+/// if the cluster could not be created at all.
+pub fn create(name: &str, provider: &str, options: CreateOptions) -> Result<ClusterState> {
+    let provider = provider::get(provider)?;
+    provider.validate(name, &options)?;
+
+    if state::exists(name) {
+        return Err(anyhow!("Cluster with name {} already exists", name));
+    }
+
+    let mut state = ClusterState::new(name, provider.name(), options)?;
+    fs::create_dir_all(state.dir())?;
+
+    if let Err(e) = provider.create(&mut state) {
+        // keep the directory of clusters that exist, so they can be deleted
+        let exists = state.remote_id.is_some()
+            || provider
+                .status(&state)
+                .map(|s| s != Status::NotFound)
+                .unwrap_or(false);
+        if !exists {
+            state.remove()?;
+            return Err(e);
+        }
+
+        state.save()?;
+        return Err(e.context(format!(
+            "Cluster {} was created but is not ready. Remove it with: hake delete --name {}",
+            name, name
+        )));
+    }
+    state.save()?;
+
+    Ok(state)
+}
+
+/// Installs the default version of `addons` into cluster `name`.
+pub fn install_addons(name: &str, addons: &[String]) -> Result<()> {
+    for addon in addons {
+        add(name, addon, &AddOptions::default())?;
+    }
+
+    Ok(())
+}
+
+/// Deletes cluster `name` and creates it again with the options it was
+/// created with, changed by the ones set in `overrides`. The addons of the
+/// old cluster are installed again, at the same versions.
+pub fn recreate(name: &str, overrides: CreateOptions) -> Result<ClusterState> {
+    let mut state = ClusterState::load(name)?;
+    let provider = provider::get(&state.provider)?;
+
+    state.options.override_with(overrides);
+    provider.validate(name, &state.options)?;
+
+    provider.recreate(&mut state)?;
+    // the new cluster starts without the addons of the old one
+    let addons_dir = format!("{}/addons", state.dir());
+    if Path::new(&addons_dir).exists() {
+        fs::remove_dir_all(addons_dir)?;
+    }
+    let installed = std::mem::take(&mut state.addons);
+    state.save()?;
+
+    for (index, addon) in installed.iter().enumerate() {
+        let options = AddOptions {
+            version: Some(addon.version.clone()),
+            no_wait: state.options.no_wait,
+            ..Default::default()
+        };
+        if let Err(e) = add(name, &addon.name, &options) {
+            let missing: Vec<String> = installed[index..]
+                .iter()
+                .map(|a| format!("{} {}", a.name, a.version))
+                .collect();
+            return Err(e.context(format!(
+                "Cluster {} was recreated without addons {}",
+                name,
+                missing.join(", ")
+            )));
+        }
+    }
+
+    ClusterState::load(name)
+}
+
+/// Directory holding the clusters, `~/.hake` unless `HAKE_HOME` is set.
+pub fn get_config_dir() -> Result<String> {
+    #[cfg(test)]
+    testing::assert_env_locked("HAKE_HOME");
+
+    if let Ok(dir) = env::var("HAKE_HOME") {
+        return Ok(dir);
+    }
+
+    let home = dirs::home_dir()
+        .ok_or_else(|| anyhow!("Could not find the home directory, set HAKE_HOME"))?;
+
+    Ok(format!("{}/.hake", home.to_string_lossy()))
+}
+
+/// Deletes the cluster and its directory.
+pub fn delete(name: &str) -> Result<()> {
+    let state = ClusterState::load(name)?;
+    provider::get(&state.provider)?.delete(&state)?;
+
+    state.remove()
+}
+
+/// Path to the kubeconfig file of cluster `name`.
+pub fn kubeconfig(name: &str) -> Result<String> {
+    let state = ClusterState::load(name)?;
+    provider::get(&state.provider)?.kubeconfig(&state)
+}
+
+/// Names of the clusters known by hake. Entries that can't be read are
+/// skipped with a warning.
+pub fn clusters() -> Result<Vec<String>> {
+    let entries = match fs::read_dir(get_config_dir()?) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut clusters = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Skipping an entry of {}: {}", get_config_dir()?, e);
+                continue;
+            }
+        };
+        // ~/.hake also holds configuration files and the cache
+        if !entry.path().is_dir() || entry.file_name() == addons::CACHE_DIR {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(name) => clusters.push(name),
+            Err(name) => log::warn!("Skipping {:?}, it is not a valid cluster name", name),
+        }
+    }
+
+    Ok(clusters)
+}
+
+/// Installs addon `name` into cluster `cluster` and waits for it to be
+/// ready. With `offline`, only the cache is used and the cached images
+/// are loaded into kind clusters.
+pub fn add(cluster: &str, name: &str, options: &AddOptions) -> Result<()> {
+    let mut state = ClusterState::load(cluster)?;
+    let kubeconfig = provider::get(&state.provider)?.kubeconfig(&state)?;
+    let addon = addons::find(name)?;
+    addon.check_provider(&state.provider)?;
+    let version = addon.version(options.version.as_deref())?;
+
+    let mut loaded_images = false;
+    if options.offline {
+        if state.provider == "kind" {
+            loaded_images = addons::load_images(&addon, version, cluster)?;
+        } else {
+            log::info!("Images can only be loaded into kind clusters, nodes will pull them");
+        }
+    }
+
+    log::info!(
+        "Installing {} {} into: {}",
+        addon.name,
+        version.version,
+        cluster
+    );
+    let manifests = addons::install(&addon, version, &kubeconfig, options.offline, loaded_images)?;
+    addons::record(&mut state, &addon, version, &manifests)?;
+
+    if options.no_wait {
+        return Ok(());
+    }
+    addons::wait(&addon, &kubeconfig, options.timeout)
+}
+
+/// Deletes what was installed for addon `name` from cluster `cluster`.
+pub fn remove(cluster: &str, name: &str) -> Result<()> {
+    let mut state = ClusterState::load(cluster)?;
+    let kubeconfig = provider::get(&state.provider)?.kubeconfig(&state)?;
+
+    log::info!("Removing {} from: {}", name, cluster);
+    addons::remove(&mut state, name, &kubeconfig)
+}
+
+/// Addons installed into cluster `cluster`.
+pub fn installed_addons(cluster: &str) -> Result<Vec<InstalledAddon>> {
+    Ok(ClusterState::load(cluster)?.addons)
+}
+
+/// Keeps `version` of addon `name`, or its default version, in the cache
+/// so it can be installed offline. Its images are saved too if `images`
+/// is set.
+pub fn fetch(name: &str, version: Option<&str>, images: bool) -> Result<()> {
+    let addon = addons::find(name)?;
+    let version = addon.version(version)?;
+
+    log::info!("Fetching {} {}", addon.name, version.version);
+    addons::fetch(&addon, version, images)
+}
+
+/// Clusters gone from their provider, see `not_found_clusters`.
+pub struct NotFound {
+    pub clusters: Vec<ClusterState>,
+    /// Clusters that could not be checked, and why.
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Clusters whose provider does not know about them anymore. A cluster
+/// that can't be checked doesn't stop the others from being checked.
+pub fn not_found_clusters() -> Result<NotFound> {
+    let mut not_found = vec![];
+    let mut failed = vec![];
+
+    for cluster in clusters()? {
+        let status = ClusterState::load(&cluster).and_then(|state| {
+            let status = provider::get(&state.provider)?.status(&state)?;
+            Ok((state, status))
+        });
+        match status {
+            Ok((state, Status::NotFound)) => not_found.push(state),
+            Ok(_) => {}
+            Err(e) => failed.push((cluster, e)),
+        }
+    }
+
+    Ok(NotFound {
+        clusters: not_found,
+        failed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::addons::{self, AddOptions};
+    use crate::cmd;
+    use crate::provider::CreateOptions;
+    use crate::state::{self, ClusterState};
+    use crate::testing::{self, Env, FakeRunner, MockServer};
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const NODES: &str = r#"{"items": [{"metadata": {"name": "flow-control-plane"},
+        "status": {"conditions": [{"type": "Ready", "status": "True"}]}}]}"#;
+
+    #[test]
+    fn test_kind_flow() {
+        let mut env = Env::new();
+        let home = env.scratch_home("kind-flow");
+        let runner = Rc::new(
+            FakeRunner::new()
+                .on("get nodes", NODES)
+                .on("get pods", r#"{"items": []}"#),
+        );
+
+        let options = CreateOptions {
+            workers: Some(1),
+            ..Default::default()
+        };
+        cmd::with_runner(runner.clone(), || {
+            testing::cluster_flow("flow", "kind", options, |state| {
+                assert_eq!(state.options.workers, Some(1));
+                assert!(Path::new(&format!("{}/kind_config", state.dir())).exists());
+
+                let overrides = CreateOptions {
+                    workers: Some(2),
+                    ..Default::default()
+                };
+                crate::recreate("flow", overrides).unwrap();
+                assert_eq!(ClusterState::load("flow").unwrap().options.workers, Some(2));
+                let config =
+                    std::fs::read_to_string(format!("{}/kind_config", state.dir())).unwrap();
+                assert_eq!(config.matches("role: worker").count(), 2);
+            })
+        });
+
+        let calls = runner.calls();
+        let create = format!(
+            "kind create cluster --name flow --kubeconfig {home}/flow/kubeconfig --config {home}/flow/kind_config",
+            home = home
+        );
+        assert_eq!(calls[0], create);
+        assert!(calls[1].ends_with("get nodes -o json"));
+        assert!(calls[2].ends_with("get pods --namespace kube-system -o json"));
+        assert_eq!(calls[3], "kind delete cluster --name flow");
+        assert_eq!(calls[4], create);
+        assert!(calls[5].ends_with("get nodes -o json"));
+        assert_eq!(calls[7], "kind delete cluster --name flow");
+    }
+
+    #[test]
+    fn test_recreate_addons() {
+        let mut env = Env::new();
+        let home = env.scratch_home("recreate-addons");
+        let cache = format!("{}/cache/addons/cert-manager/1.11.4", home);
+        std::fs::create_dir_all(&cache).unwrap();
+        std::fs::write(format!("{}/0-cert-manager.yaml", cache), "kind: Namespace").unwrap();
+        std::fs::write(
+            format!("{}/sha256sums", cache),
+            format!(
+                "{}  0-cert-manager.yaml\n",
+                addons::sha256(b"kind: Namespace")
+            ),
+        )
+        .unwrap();
+
+        let options = CreateOptions {
+            no_wait: true,
+            ..Default::default()
+        };
+        let runner = Rc::new(FakeRunner::new());
+        let err = cmd::with_runner(runner.clone(), || -> anyhow::Result<String> {
+            crate::create("tools", "kind", options.clone())?;
+            let add = AddOptions {
+                version: Some(String::from("1.11")),
+                no_wait: true,
+                ..Default::default()
+            };
+            crate::add("tools", "cert-manager", &add)?;
+            crate::recreate("tools", options.clone())?;
+
+            // addons that can't be installed again are named
+            std::fs::write(format!("{}/0-cert-manager.yaml", cache), "kind: Secret")?;
+            Ok(crate::recreate("tools", options).err().unwrap().to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            err,
+            "Cluster tools was recreated without addons cert-manager 1.11.4"
+        );
+
+        assert!(crate::installed_addons("tools").unwrap().is_empty());
+        let create = format!(
+            "kind create cluster --name tools --kubeconfig {home}/tools/kubeconfig --config {home}/tools/kind_config",
+            home = home
+        );
+        let apply = format!("kubectl --kubeconfig {}/tools/kubeconfig apply -f -", home);
+        let delete = String::from("kind delete cluster --name tools");
+        assert_eq!(
+            runner.calls(),
+            vec![
+                create.clone(),
+                apply.clone(),
+                delete.clone(),
+                create.clone(),
+                apply,
+                delete,
+                create
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add() {
+        let mut env = Env::new();
+        let home = env.scratch_home("add");
+
+        assert_eq!(
+            crate::add("missing", "cert-manager", &AddOptions::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "Cluster missing does not exist"
+        );
+
+        let state = ClusterState::new("addons", "kind", CreateOptions::default()).unwrap();
+        std::fs::create_dir_all(state.dir()).unwrap();
+        state.save().unwrap();
+        let other = ClusterState::new("other", "k3d", CreateOptions::default()).unwrap();
+        std::fs::create_dir_all(other.dir()).unwrap();
+        other.save().unwrap();
+        assert_eq!(
+            crate::add("other", "ingress-nginx", &AddOptions::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "ingress-nginx can only be installed into kind clusters"
+        );
+        let cache = format!("{}/cache/addons/cert-manager/1.12.3", home);
+        std::fs::create_dir_all(&cache).unwrap();
+        std::fs::write(format!("{}/0-cert-manager.yaml", cache), "kind: Namespace").unwrap();
+        std::fs::write(
+            format!("{}/sha256sums", cache),
+            format!(
+                "{}  0-cert-manager.yaml\n",
+                addons::sha256(b"kind: Namespace")
+            ),
+        )
+        .unwrap();
+
+        let options = AddOptions {
+            version: Some(String::from("1.12")),
+            offline: true,
+            no_wait: true,
+            ..Default::default()
+        };
+        let runner = Rc::new(FakeRunner::new());
+        cmd::with_runner(runner.clone(), || {
+            crate::add("addons", "cert-manager", &options)
+        })
+        .unwrap();
+        let installed = crate::installed_addons("addons").unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(
+            (&installed[0].name[..], &installed[0].version[..]),
+            ("cert-manager", "1.12.3")
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/addons/addons/cert-manager/0.yaml", home)).unwrap(),
+            "kind: Namespace"
+        );
+
+        cmd::with_runner(runner.clone(), || crate::remove("addons", "cert-manager")).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!("kubectl --kubeconfig {}/addons/kubeconfig apply -f -", home),
+                format!(
+                    "kubectl --kubeconfig {}/addons/kubeconfig delete --ignore-not-found -f -",
+                    home
+                ),
+            ]
+        );
+        assert!(crate::installed_addons("addons").unwrap().is_empty());
+        assert!(!Path::new(&format!("{}/addons/addons/cert-manager", home)).exists());
+        assert_eq!(
+            crate::remove("addons", "cert-manager")
+                .err()
+                .unwrap()
+                .to_string(),
+            "Addon cert-manager is not installed in cluster addons"
+        );
+    }
+
+    #[test]
+    fn test_kind_create_rollback() {
+        let mut env = Env::new();
+        env.scratch_home("kind-rollback");
+        let runner = Rc::new(FakeRunner::new().fail("kind create", "ERROR: no space left"));
+
+        let err = cmd::with_runner(runner, || {
+            crate::create("broken", "kind", CreateOptions::default())
+        })
+        .err()
+        .unwrap();
+
+        assert!(err
+            .to_string()
+            .ends_with("failed with exit code 1:\nERROR: no space left"));
+        assert!(!state::exists("broken"));
+    }
+
+    #[test]
+    fn test_digitalocean_flow() {
+        let server = MockServer::start(|method, path, _| match (method, path) {
+            ("POST", "/v2/kubernetes/clusters") => (
+                201,
+                String::from(
+                    r#"{"kubernetes_cluster": {"id": "abc", "name": "remote", "region": "lon1",
+                        "version": "1.27.4-do.0", "node_pools": []}}"#,
+                ),
+            ),
+            ("GET", "/v2/kubernetes/clusters/abc") => (
+                200,
+                String::from(
+                    r#"{"kubernetes_cluster": {"id": "abc", "name": "remote", "region": "lon1",
+                        "version": "1.27.4-do.0", "status": {"state": "running"},
+                        "node_pools": [{"name": "pool", "size": "s-1vcpu-2gb", "count": 1,
+                            "nodes": [{"id": "n1", "name": "n1", "status": {"state": "running"},
+                                       "droplet_id": "42", "created_at": "", "updated_at": ""}]}]}}"#,
+                ),
+            ),
+            ("GET", "/v2/kubernetes/clusters/abc/kubeconfig") => {
+                (200, String::from("apiVersion: v1"))
+            }
+            ("GET", "/v2/load_balancers") => (200, String::from(r#"{"load_balancers": []}"#)),
+            ("DELETE", "/v2/kubernetes/clusters/abc") => (204, String::new()),
+            _ => (404, String::new()),
+        });
+
+        let mut env = Env::new();
+        let home = env.scratch_home("do-flow");
+        env.set("HAKE_PROVIDER_DIGITALOCEAN_API_KEY", "token");
+        env.set(
+            "HAKE_PROVIDER_DIGITALOCEAN_API_URL",
+            &format!("{}/v2", server.url),
+        );
+
+        testing::cluster_flow("remote", "do", CreateOptions::default(), |state| {
+            assert_eq!(state.provider, "digitalocean");
+            assert_eq!(state.remote_id, Some(String::from("abc")));
+            assert_eq!(
+                std::fs::read_to_string(format!("{}/kubeconfig", state.dir())).unwrap(),
+                "apiVersion: v1"
+            );
+
+            // clusters gone from DigitalOcean are cleaned up
+            let mut gone =
+                ClusterState::new("gone", "digitalocean", CreateOptions::default()).unwrap();
+            gone.remote_id = Some(String::from("gone"));
+            std::fs::create_dir_all(gone.dir()).unwrap();
+            gone.save().unwrap();
+            // a broken cluster doesn't stop the others from being checked
+            std::fs::create_dir_all(format!("{}/broken", home)).unwrap();
+            std::fs::write(format!("{}/broken/state.json", home), "not json").unwrap();
+            let not_found = crate::not_found_clusters().unwrap();
+            let gone: Vec<&str> = not_found.clusters.iter().map(|s| &s.name[..]).collect();
+            assert_eq!(gone, vec!["gone"]);
+            let failed: Vec<&str> = not_found.failed.iter().map(|(n, _)| &n[..]).collect();
+            assert_eq!(failed, vec!["broken"]);
+        });
+
+        // clusters are looked at in no particular order
+        let requests = server.requests();
+        assert_eq!(
+            requests[..3].to_vec(),
+            vec![
+                "POST /v2/kubernetes/clusters",
+                "GET /v2/kubernetes/clusters/abc",
+                "GET /v2/kubernetes/clusters/abc/kubeconfig",
+            ]
+        );
+        assert!(requests.contains(&String::from("GET /v2/kubernetes/clusters/gone")));
+        assert_eq!(
+            requests[requests.len() - 3..].to_vec(),
+            vec![
+                "GET /v2/kubernetes/clusters/abc",
+                "GET /v2/load_balancers",
+                "DELETE /v2/kubernetes/clusters/abc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_digitalocean_recreate() {
+        // clusters are gone once deleted, and get a new id when created
+        let created = Arc::new(AtomicUsize::new(0));
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let server = MockServer::start(move |method, path, _| {
+            let cluster = path.strip_prefix("/v2/kubernetes/clusters/");
+            let id = cluster.map(|c| String::from(c.split('/').next().unwrap()));
+            match (method, path, id) {
+                ("POST", "/v2/kubernetes/clusters", _) => {
+                    let id = ["abc", "def"][created.fetch_add(1, Ordering::SeqCst)];
+                    (
+                        201,
+                        format!(
+                            r#"{{"kubernetes_cluster": {{"id": "{}", "name": "remote",
+                                "region": "lon1", "version": "1.27.4-do.0", "node_pools": []}}}}"#,
+                            id
+                        ),
+                    )
+                }
+                ("GET", "/v2/load_balancers", _) => {
+                    (200, String::from(r#"{"load_balancers": []}"#))
+                }
+                (_, _, Some(id)) if deleted.lock().unwrap().contains(&id) => (404, String::new()),
+                ("DELETE", _, Some(id)) => {
+                    deleted.lock().unwrap().push(id);
+                    (204, String::new())
+                }
+                ("GET", path, Some(_)) if path.ends_with("/kubeconfig") => {
+                    (200, String::from("apiVersion: v1"))
+                }
+                ("GET", _, Some(id)) => (
+                    200,
+                    format!(
+                        r#"{{"kubernetes_cluster": {{"id": "{}", "name": "remote",
+                            "region": "lon1", "version": "1.27.4-do.0",
+                            "status": {{"state": "running"}}, "node_pools": []}}}}"#,
+                        id
+                    ),
+                ),
+                _ => (404, String::new()),
+            }
+        });
+
+        let mut env = Env::new();
+        env.scratch_home("do-recreate");
+        env.set("HAKE_PROVIDER_DIGITALOCEAN_API_KEY", "token");
+        env.set(
+            "HAKE_PROVIDER_DIGITALOCEAN_API_URL",
+            &format!("{}/v2", server.url),
+        );
+
+        crate::create("remote", "do", CreateOptions::default()).unwrap();
+        let state = crate::recreate("remote", CreateOptions::default()).unwrap();
+        assert_eq!(state.remote_id, Some(String::from("def")));
+        assert_eq!(
+            ClusterState::load("remote").unwrap().remote_id,
+            Some(String::from("def"))
+        );
+
+        let requests = server.requests();
+        let deleted = requests
+            .iter()
+            .position(|r| r == "DELETE /v2/kubernetes/clusters/abc")
+            .unwrap();
+        assert_eq!(
+            requests[deleted + 1..deleted + 3].to_vec(),
+            vec![
+                "GET /v2/kubernetes/clusters/abc",
+                "POST /v2/kubernetes/clusters",
+            ]
+        );
+    }
+}
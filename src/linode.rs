@@ -0,0 +1,529 @@
+///
+/// Linode Kubernetes Engine
+///
+use anyhow::{anyhow, Context, Result};
+
+use std::convert::TryFrom;
+use std::time::Duration;
+use std::vec::Vec;
+
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::provider::{self, ClusterInfo, CreateOptions, Provider, Status};
+use crate::rest::{self, parse_bool, parse_count, parse_list, Api};
+use crate::state::ClusterState;
+use crate::wait::{self, Progress};
+
+const ENV_LINODE_PROVIDER: &str = "HAKE_PROVIDER_LINODE_API_KEY";
+/// Base URL of the API, can be changed for proxies and tests.
+const ENV_LINODE_API_URL: &str = "HAKE_PROVIDER_LINODE_API_URL";
+const DEFAULT_API_URL: &str = "https://api.linode.com/v4";
+
+/// Nodes take a few minutes to boot and join the cluster.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+const DEFAULT_REGION: &str = "us-east";
+const DEFAULT_VERSION: &str = "1.27";
+const DEFAULT_NODEPOOL_TYPE: &str = "g6-standard-2";
+const DEFAULT_NODEPOOL_COUNT: u16 = 3;
+
+#[derive(Serialize, Debug)]
+struct NewNodePool {
+    #[serde(rename = "type")]
+    node_type: String,
+    count: u16,
+}
+
+#[derive(Serialize, Debug)]
+struct ControlPlane {
+    high_availability: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct NewCluster {
+    label: String,
+    region: String,
+    k8s_version: String,
+    node_pools: Vec<NewNodePool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    control_plane: ControlPlane,
+}
+
+#[derive(Deserialize, Debug)]
+struct Cluster {
+    id: u64,
+    label: String,
+    k8s_version: String,
+    #[serde(default)]
+    status: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PoolNode {
+    status: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NodePool {
+    count: u16,
+    #[serde(default)]
+    nodes: Vec<PoolNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Kubeconfig {
+    /// Base64 encoded.
+    kubeconfig: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Metadata {
+    region: String,
+    version: String,
+    ha: bool,
+    tags: Vec<String>,
+    node_type: String,
+    count: u16,
+}
+
+impl Metadata {
+    /// Parses metadata like `region=eu-west&nodepool.type=g6-standard-4`.
+    /// The Kubernetes version comes from `--k8s-version`, and the number of
+    /// nodes from `--workers` unless `nodepool.count` is given.
+    fn from_options(options: &CreateOptions) -> Result<Metadata> {
+        let data = options.metadata.as_deref().unwrap_or_default();
+        let mut metadata = Metadata {
+            region: String::from(DEFAULT_REGION),
+            version: String::from(DEFAULT_VERSION),
+            ha: false,
+            tags: vec![],
+            node_type: String::from(DEFAULT_NODEPOOL_TYPE),
+            count: DEFAULT_NODEPOOL_COUNT,
+        };
+        let mut errors = rest::invalid_fields(data);
+
+        if let Some(workers) = options.workers {
+            match u16::try_from(workers) {
+                Ok(count) => metadata.count = count,
+                Err(_) => errors.push(format!("workers: must be at most {}", u16::MAX)),
+            }
+        }
+
+        if let Some(version) = &options.k8s_version {
+            // LKE only takes minor versions
+            let re = Regex::new(r"^v?(\d+\.\d+)(\.\d+)?$").unwrap();
+            match re.captures(version) {
+                Some(cap) => metadata.version = String::from(&cap[1]),
+                None => errors.push(format!(
+                    "k8s_version: expected a version like 1.27, got \"{}\"",
+                    version
+                )),
+            }
+        }
+
+        let map = rest::parse_metadata(data);
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+
+        for key in keys {
+            let value = &map[key][..];
+            let result = match &key[..] {
+                "region" => {
+                    metadata.region = String::from(value);
+                    Ok(())
+                }
+                "ha" => parse_bool(key, value).map(|v| metadata.ha = v),
+                "tags" => {
+                    metadata.tags = parse_list(value);
+                    Ok(())
+                }
+                "nodepool.type" => {
+                    metadata.node_type = String::from(value);
+                    Ok(())
+                }
+                "nodepool.count" => parse_count(key, value).map(|v| metadata.count = v),
+                _ => Err(format!("{}: unknown metadata key", key)),
+            };
+
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+
+        if metadata.count == 0 {
+            errors.push(String::from("nodepool.count: must be at least 1"));
+        }
+
+        rest::metadata_errors("Linode", errors)?;
+
+        Ok(metadata)
+    }
+
+    fn to_cluster(&self, name: &str) -> NewCluster {
+        NewCluster {
+            label: String::from(name),
+            region: self.region.clone(),
+            k8s_version: self.version.clone(),
+            node_pools: vec![NewNodePool {
+                node_type: self.node_type.clone(),
+                count: self.count,
+            }],
+            tags: self.tags.clone(),
+            control_plane: ControlPlane {
+                high_availability: self.ha,
+            },
+        }
+    }
+}
+
+/// Clusters are labelled with their name, and labels are more restricted
+/// than hake names.
+fn validate_label(name: &str) -> Result<()> {
+    let re = Regex::new(r"^[a-zA-Z0-9]([-a-zA-Z0-9]{0,30}[a-zA-Z0-9])?$").unwrap();
+    if !re.is_match(name) {
+        return Err(anyhow!(
+            "Invalid name for a Linode cluster: {}. It needs to be up to 32 alphanumeric characters or '-'",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+fn api() -> Result<Api> {
+    Api::new(
+        "linode",
+        ENV_LINODE_PROVIDER,
+        ENV_LINODE_API_URL,
+        DEFAULT_API_URL,
+    )
+}
+
+fn get_cluster_id(state: &ClusterState) -> Result<&str> {
+    state
+        .remote_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("Cluster {} has no Linode id", state.name))
+}
+
+/// The cluster is ready once every node of its pools is.
+fn provisioning_progress(pools: &[NodePool]) -> Progress {
+    let expected: usize = pools.iter().map(|p| p.count as usize).sum();
+    let ready = pools
+        .iter()
+        .flat_map(|p| p.nodes.iter())
+        .filter(|n| n.status == "ready")
+        .count();
+
+    if ready < expected {
+        Progress::Pending(format!("{}/{} nodes ready", ready, expected))
+    } else {
+        Progress::Done
+    }
+}
+
+fn decode_kubeconfig(body: String) -> Result<String> {
+    let kubeconfig: Kubeconfig = serde_json::from_str(&body)?;
+    let decoded = base64::decode(&kubeconfig.kubeconfig).context("Invalid kubeconfig")?;
+
+    Ok(String::from_utf8(decoded)?)
+}
+
+pub fn create(state: &mut ClusterState) -> Result<()> {
+    let metadata = Metadata::from_options(&state.options)?;
+
+    let api = api()?;
+    let cluster: Cluster = api
+        .post("/lke/clusters", &metadata.to_cluster(&state.name))
+        .context("Could not create cluster")?;
+
+    let cluster_id = cluster.id.to_string();
+    log::info!("Cluster created with id: {}", cluster_id);
+
+    // record the id straight away, so the cluster can be deleted even if
+    // getting its kubeconfig fails.
+    state.remote_id = Some(cluster_id.clone());
+    state.save()?;
+
+    let timeout = state.options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    api.download_kubeconfig(
+        &format!("/lke/clusters/{}/kubeconfig", cluster_id),
+        &format!("{}/kubeconfig", state.dir()),
+        timeout,
+        decode_kubeconfig,
+    )?;
+
+    if !state.options.no_wait {
+        wait::until(&format!("cluster {}", state.name), timeout, || {
+            let pools: Result<Vec<NodePool>> =
+                api.list(&format!("/lke/clusters/{}/pools", cluster_id), "data");
+            rest::retry_transient(pools.map(|pools| provisioning_progress(&pools)))
+        })?;
+    }
+
+    Ok(())
+}
+
+pub fn delete(state: &ClusterState) -> Result<()> {
+    let cluster_id = get_cluster_id(state)?;
+
+    log::info!("Removing Cluster: {}", cluster_id);
+    api()?
+        .delete(&format!("/lke/clusters/{}", cluster_id))
+        .with_context(|| format!("Could not remove Cluster with id: {}", cluster_id))
+}
+
+/// Waits for a deleted cluster to be gone, so a new one can take its label.
+fn wait_until_deleted(state: &ClusterState) -> Result<()> {
+    let cluster_id = get_cluster_id(state)?;
+    let api = api()?;
+
+    let timeout = state.options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    wait::until(
+        &format!("cluster {} to be deleted", state.name),
+        timeout,
+        || {
+            let cluster: Result<Option<Cluster>> =
+                api.get(&format!("/lke/clusters/{}", cluster_id));
+            rest::retry_transient(cluster.map(|cluster| match cluster {
+                Some(cluster) => Progress::Pending(format!("cluster is {}", cluster.status)),
+                None => Progress::Done,
+            }))
+        },
+    )
+}
+
+fn get_info(state: &ClusterState) -> Result<ClusterInfo> {
+    let cluster_id = get_cluster_id(state)?;
+    let api = api()?;
+
+    let cluster: Cluster = match api.get(&format!("/lke/clusters/{}", cluster_id))? {
+        Some(cluster) => cluster,
+        None => return Ok(ClusterInfo::from(Status::NotFound)),
+    };
+    let pools: Vec<NodePool> = api.list(&format!("/lke/clusters/{}/pools", cluster_id), "data")?;
+
+    let status = match (&cluster.status[..], provisioning_progress(&pools)) {
+        ("ready", Progress::Done) => Status::Running,
+        ("ready", Progress::Pending(message)) => Status::Provisioning(message),
+        ("", _) => Status::Provisioning(String::from("unknown")),
+        (status, _) => Status::Provisioning(String::from(status)),
+    };
+
+    Ok(ClusterInfo {
+        status,
+        version: Some(cluster.k8s_version),
+        nodes: Some(pools.iter().map(|p| p.count as usize).sum()),
+    })
+}
+
+fn list_clusters() -> Result<Vec<String>> {
+    let clusters: Vec<Cluster> = api()?.list("/lke/clusters", "data")?;
+
+    Ok(clusters.into_iter().map(|c| c.label).collect())
+}
+
+pub struct Linode;
+
+impl Provider for Linode {
+    fn name(&self) -> &str {
+        "linode"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["lke"]
+    }
+
+    fn validate(&self, name: &str, options: &CreateOptions) -> Result<()> {
+        validate_label(name)?;
+        provider::reject_unsupported(self.name(), &provider::managed_options(options))?;
+        Metadata::from_options(options)?;
+
+        Ok(())
+    }
+
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        create(state)
+    }
+
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        delete(state)
+    }
+
+    /// Linode refuses new clusters labeled like one being deleted.
+    fn recreate(&self, state: &mut ClusterState) -> Result<()> {
+        delete(state)?;
+        wait_until_deleted(state)?;
+        // so a failed create doesn't leave the old id behind
+        state.remote_id = None;
+        state.save()?;
+
+        create(state)
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        Ok(get_info(state)?.status)
+    }
+
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        get_info(state)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        list_clusters()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::linode::{self, Linode, Metadata, NodePool};
+    use crate::provider::{CreateOptions, Provider};
+    use crate::testing::{self, Env, MockServer};
+    use crate::wait::Progress;
+
+    #[test]
+    fn test_metadata_from_options() {
+        let options = CreateOptions {
+            workers: Some(2),
+            k8s_version: Some(String::from("1.26.3")),
+            metadata: Some(String::from(
+                "region=eu-west&ha=true&nodepool.type=g6-standard-4",
+            )),
+            ..Default::default()
+        };
+        let metadata = Metadata::from_options(&options).unwrap();
+        assert_eq!(
+            metadata,
+            Metadata {
+                region: String::from("eu-west"),
+                version: String::from("1.26"),
+                ha: true,
+                tags: vec![],
+                node_type: String::from("g6-standard-4"),
+                count: 2,
+            }
+        );
+
+        let options = CreateOptions {
+            workers: Some(70000),
+            k8s_version: Some(String::from("latest")),
+            metadata: Some(String::from("nodepool.count=0&size=big")),
+            ..Default::default()
+        };
+        assert_eq!(
+            Metadata::from_options(&options).err().unwrap().to_string(),
+            "Invalid Linode metadata:\
+             \n  - workers: must be at most 65535\
+             \n  - k8s_version: expected a version like 1.27, got \"latest\"\
+             \n  - size: unknown metadata key\
+             \n  - nodepool.count: must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let options = CreateOptions {
+            control_planes: Some(3),
+            node_taints: vec![String::from("all:dedicated=db:NoSchedule")],
+            port_mappings: vec![String::from("80:80")],
+            ..Default::default()
+        };
+        assert_eq!(
+            Linode.validate("tests", &options).err().unwrap().to_string(),
+            "The linode provider does not support multiple control planes, node taints, port mappings"
+        );
+
+        // names are checked before the cluster directory is created
+        assert!(Linode
+            .validate("hake_1_27", &CreateOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_label() {
+        assert!(linode::validate_label("hake-1-27").is_ok());
+        assert!(linode::validate_label("hake_1_27").is_err());
+        assert!(linode::validate_label(&"a".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn test_provisioning_progress() {
+        let pools: Vec<NodePool> = serde_json::from_str(
+            r#"[{"count": 2, "nodes": [{"status": "ready"}, {"status": "not_ready"}]}]"#,
+        )
+        .unwrap();
+
+        match linode::provisioning_progress(&pools) {
+            Progress::Pending(message) => assert_eq!(message, "1/2 nodes ready"),
+            Progress::Done => panic!("the cluster is not ready"),
+        }
+    }
+
+    #[test]
+    fn test_flow() {
+        let server = MockServer::start(|method, path, body| match (method, path) {
+            ("POST", "/v4/lke/clusters") => {
+                assert!(body.contains(r#""label":"lke-test""#));
+                (
+                    200,
+                    String::from(
+                        r#"{"id": 42, "label": "lke-test", "k8s_version": "1.27", "status": "ready"}"#,
+                    ),
+                )
+            }
+            ("GET", "/v4/lke/clusters/42/kubeconfig") => (
+                200,
+                format!(
+                    r#"{{"kubeconfig": "{}"}}"#,
+                    base64::encode("apiVersion: v1")
+                ),
+            ),
+            ("GET", "/v4/lke/clusters/42/pools") => (
+                200,
+                String::from(
+                    r#"{"data": [{"id": 1, "type": "g6-standard-2", "count": 1,
+                        "nodes": [{"id": "1-a", "instance_id": 7, "status": "ready"}]}],
+                        "page": 1, "pages": 1, "results": 1}"#,
+                ),
+            ),
+            ("DELETE", "/v4/lke/clusters/42") => (200, String::from("{}")),
+            _ => (
+                404,
+                String::from(r#"{"errors": [{"reason": "Not found"}]}"#),
+            ),
+        });
+
+        let mut env = Env::new();
+        env.scratch_home("linode-flow");
+        env.set("HAKE_PROVIDER_LINODE_API_KEY", "token");
+        env.set(
+            "HAKE_PROVIDER_LINODE_API_URL",
+            &format!("{}/v4", server.url),
+        );
+
+        let options = CreateOptions {
+            workers: Some(1),
+            ..Default::default()
+        };
+        testing::cluster_flow("lke-test", "lke", options, |state| {
+            assert_eq!(state.provider, "linode");
+            assert_eq!(state.remote_id, Some(String::from("42")));
+            assert_eq!(
+                std::fs::read_to_string(format!("{}/kubeconfig", state.dir())).unwrap(),
+                "apiVersion: v1"
+            );
+        });
+
+        assert_eq!(
+            server.requests(),
+            vec![
+                "POST /v4/lke/clusters",
+                "GET /v4/lke/clusters/42/kubeconfig",
+                "GET /v4/lke/clusters/42/pools",
+                "DELETE /v4/lke/clusters/42",
+            ]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// Listing of the clusters known by hake, along with their live status.
+
+use anyhow::{anyhow, Result};
+use serde_derive::Serialize;
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::provider::{self, Status};
+use crate::state::ClusterState;
+
+#[derive(Debug)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(anyhow!(
+                "Unknown output format: {}. Use one of: table, json, yaml",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ListEntry {
+    pub name: String,
+    pub provider: String,
+    pub version: Option<String>,
+    pub nodes: Option<usize>,
+    pub created_at: Option<u64>,
+    /// One of running, provisioning, not-found, unreachable or invalid.
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ListEntry {
+    fn new(name: &str) -> ListEntry {
+        ListEntry {
+            name: String::from(name),
+            provider: String::from("unknown"),
+            version: None,
+            nodes: None,
+            created_at: None,
+            status: String::from("invalid"),
+            message: None,
+        }
+    }
+
+    fn from_cluster(name: &str) -> ListEntry {
+        let mut entry = ListEntry::new(name);
+
+        let state = match ClusterState::load(name) {
+            Ok(state) => state,
+            Err(e) => {
+                entry.message = Some(e.to_string());
+                return entry;
+            }
+        };
+        entry.provider = state.provider.clone();
+        entry.created_at = Some(state.created_at);
+
+        let info = provider::get(&state.provider).and_then(|p| p.info(&state));
+        match info {
+            Ok(info) => {
+                entry.version = info.version;
+                entry.nodes = info.nodes;
+                entry.status = match info.status {
+                    Status::Running => String::from("running"),
+                    Status::NotFound => String::from("not-found"),
+                    Status::Provisioning(message) => {
+                        entry.message = Some(message);
+                        String::from("provisioning")
+                    }
+                };
+            }
+            Err(e) => {
+                entry.status = String::from("unreachable");
+                entry.message = Some(e.to_string());
+            }
+        }
+
+        entry
+    }
+}
+
+/// Formats the time elapsed since `created_at` like `5m`, `3h` or `2d`.
+pub(crate) fn age(created_at: u64, now: u64) -> String {
+    let secs = now.saturating_sub(created_at);
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 60 * 60 * 24 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (60 * 60 * 24)),
+    }
+}
+
+/// Formats the entries as a table, like `kubectl get` does.
+pub fn table(entries: &[ListEntry]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| String::from("-"));
+
+    let mut rows = vec![vec![
+        String::from("NAME"),
+        String::from("PROVIDER"),
+        String::from("VERSION"),
+        String::from("NODES"),
+        String::from("AGE"),
+        String::from("STATUS"),
+    ]];
+    for entry in entries {
+        let status = match &entry.message {
+            Some(message) => format!("{} ({})", entry.status, message),
+            None => entry.status.clone(),
+        };
+        rows.push(vec![
+            entry.name.clone(),
+            entry.provider.clone(),
+            or_dash(entry.version.clone()),
+            or_dash(entry.nodes.map(|n| n.to_string())),
+            or_dash(entry.created_at.map(|c| age(c, now))),
+            status,
+        ]);
+    }
+
+    columns(rows)
+}
+
+/// Lines up `rows`, the first one being the header.
+pub(crate) fn columns(rows: Vec<Vec<String>>) -> String {
+    let columns = rows[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or(0))
+        .collect();
+
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        out.push_str(line.join("   ").trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Looks up every cluster, a cluster that can't be reached still gets an
+/// entry.
+pub fn entries(clusters: &[String]) -> Vec<ListEntry> {
+    clusters
+        .iter()
+        .map(|c| ListEntry::from_cluster(c))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::list::{self, ListEntry};
+
+    #[test]
+    fn test_age() {
+        assert_eq!(list::age(100, 130), "30s");
+        assert_eq!(list::age(0, 60 * 5), "5m");
+        assert_eq!(list::age(0, 60 * 60 * 3 + 10), "3h");
+        assert_eq!(list::age(0, 60 * 60 * 24 * 2), "2d");
+        assert_eq!(list::age(100, 50), "0s");
+    }
+
+    #[test]
+    fn test_table() {
+        let mut entry = ListEntry::new("hake-default");
+        entry.provider = String::from("kind");
+        entry.status = String::from("unreachable");
+        entry.message = Some(String::from("docker is not running"));
+
+        assert_eq!(
+            list::table(&[entry]),
+            "NAME           PROVIDER   VERSION   NODES   AGE   STATUS\n\
+             hake-default   kind       -         -       -     unreachable (docker is not running)\n"
+        );
+    }
+}
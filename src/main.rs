@@ -1,61 +1,58 @@
 use anyhow::Result;
 
-mod add;
-mod r#do;
-mod kind;
-
-use std::fs;
-use std::path::Path;
+use std::time::Duration;
 use std::vec::Vec;
 
 use console::Style;
-
-use crate::kind::Kind;
+use log::{Level, LevelFilter, Metadata, Record};
+
+use hake::addons::{self, AddOptions};
+use hake::list;
+use hake::matrix;
+use hake::ports;
+use hake::provider::CreateOptions;
+use hake::registry;
+use hake::spec::ClusterSpec;
+use hake::state;
+use hake::wait;
 use structopt::StructOpt;
 
 const DEFAULT_NAME: &str = "hake-default";
 const DEFAULT_PROVIDER: &str = "kind";
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "Kind")]
-/// The kind starter with simpler advanced options.
+#[structopt(name = "hake")]
+#[allow(clippy::large_enum_variant)]
+/// Starts test Kubernetes clusters with kind, k3d, minikube, DigitalOcean,
+/// Linode or provider plugins.
 enum Opt {
-    /// Creates a kind cluster
+    /// Creates a cluster with any provider
     Create {
-        /// Name of the cluster
-        #[structopt(long, default_value = DEFAULT_NAME)]
-        name: String,
+        /// Cluster spec file. Other options override the values in it
+        #[structopt(long, short)]
+        file: Option<String>,
 
-        /// Configures access to an ECR private registry
+        /// Name of the cluster [default: hake-default]
         #[structopt(long)]
-        ecr: Option<String>,
+        name: Option<String>,
 
-        /// Configure access to local Docker registry
+        /// Provider [default: kind]
         #[structopt(long)]
-        use_local_registry: Option<String>,
-
-        /// Pass extra port mappings
-        #[structopt(long)]
-        extra_port_mappings: Option<String>,
-
-        /// Verbose
-        #[structopt(short)]
-        verbose: bool,
-
-        /// Provider
-        #[structopt(long, default_value = DEFAULT_PROVIDER)]
-        provider: String,
+        provider: Option<String>,
 
-        /// Metadata
-        #[structopt(long)]
-        metadata: Option<String>,
+        #[structopt(flatten)]
+        cluster: ClusterOpt,
     },
-    /// Recreates a cluster by name
+    /// Recreates a cluster by name, with the options it was created with
     Recreate {
         #[structopt(long, default_value = DEFAULT_NAME)]
         name: String,
+
+        // options to change, lists replace the ones the cluster was created with
+        #[structopt(flatten)]
+        cluster: ClusterOpt,
     },
-    /// Deletes a kind cluster
+    /// Deletes a cluster
     Delete {
         /// Name of the cluster
         #[structopt(long, default_value = DEFAULT_NAME)]
@@ -68,37 +65,253 @@ enum Opt {
         name: String,
     },
     /// Display list of known clusters
-    List,
+    List {
+        /// Output format: table, json or yaml
+        #[structopt(long, short, default_value = "table")]
+        output: list::OutputFormat,
+    },
     /// Removes clusters that are not reachable anymore
     Clean {
         /// Force removal of directories
         #[structopt(long)]
         force: bool,
     },
-    /// Adds a capability
+    /// Creates or deletes one cluster per Kubernetes version
+    Matrix(MatrixOpt),
+    /// Manages a local registry that kind, k3d and minikube clusters can pull from
+    Registry(RegistryOpt),
+    /// Installs an addon into a cluster
     Add {
-        /// name of the capability
+        /// Name of the addon, see `hake addons list`
+        name: String,
+
+        /// Name of the cluster
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        cluster: String,
+
+        /// Version of the addon, like 1.12 [default: the addon's default]
         #[structopt(long)]
+        version: Option<String>,
+
+        /// Only use the cache, filled with `hake addons fetch`
+        #[structopt(long)]
+        offline: bool,
+
+        /// How long to wait for the addon to be ready, like 90s or 10m [default: 5m]
+        #[structopt(long, parse(try_from_str = wait::parse_duration))]
+        timeout: Option<Duration>,
+
+        /// Do not wait for the addon to be ready
+        #[structopt(long)]
+        no_wait: bool,
+    },
+    /// Removes an addon installed with add from a cluster
+    Remove {
+        /// Name of the addon
         name: String,
+
+        /// Name of the cluster
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        cluster: String,
     },
+    /// Lists the addons that can be installed, and caches them
+    Addons(AddonsOpt),
+}
+
+/// Options shared by create and recreate.
+#[derive(StructOpt, Debug)]
+struct ClusterOpt {
+    /// Configures access to an ECR private registry
+    #[structopt(long)]
+    ecr: Option<String>,
+
+    /// Configure access to local Docker registry
+    #[structopt(long)]
+    use_local_registry: Option<String>,
+
+    /// Port mapping, like [listenAddress:]hostPort:containerPort[/protocol]. Can be repeated
+    #[structopt(long = "port", number_of_values = 1)]
+    port_mappings: Vec<String>,
+
+    /// Deprecated, use --port. Port mapping like containerPort:hostPort[:TCP]
+    #[structopt(long, number_of_values = 1, hidden = true)]
+    extra_port_mappings: Vec<String>,
+
+    /// Number of control-plane nodes [default: 1]
+    #[structopt(long)]
+    control_planes: Option<u32>,
+
+    /// Number of worker nodes [default: 0]
+    #[structopt(long)]
+    workers: Option<u32>,
+
+    /// Node label, like worker:disk=ssd or worker-2:disk=ssd. Can be repeated
+    #[structopt(long = "node-label", number_of_values = 1)]
+    node_labels: Vec<String>,
+
+    /// Node taint, like worker-1:dedicated=db:NoSchedule. Can be repeated
+    #[structopt(long = "node-taint", number_of_values = 1)]
+    node_taints: Vec<String>,
+
+    /// Kubernetes version, like 1.27
+    #[structopt(long)]
+    k8s_version: Option<String>,
+
+    /// Node image or version for kind, like worker-1:kindest/node:v1.26.6 or worker-1:1.26. Can be repeated
+    #[structopt(long = "node-image", number_of_values = 1)]
+    node_images: Vec<String>,
+
+    /// Verbose
+    #[structopt(short)]
+    verbose: bool,
+
+    /// How long to wait for the cluster to be ready, like 90s or 10m
+    #[structopt(long, alias = "wait", parse(try_from_str = wait::parse_duration))]
+    timeout: Option<Duration>,
+
+    /// Do not wait for the cluster to be ready
+    #[structopt(long)]
+    no_wait: bool,
+
+    /// Metadata
+    #[structopt(long)]
+    metadata: Option<String>,
 }
 
-enum ClusterType {
-    Kind,
-    DigitalOcean,
+impl ClusterOpt {
+    fn options(self) -> Result<CreateOptions> {
+        let mut port_mappings = self.port_mappings;
+        for legacy in &self.extra_port_mappings {
+            let mapping = ports::from_legacy(legacy)?;
+            eprintln!(
+                "--extra-port-mappings is deprecated and maps containerPort:hostPort, use: --port {}",
+                mapping
+            );
+            port_mappings.push(mapping);
+        }
+
+        Ok(CreateOptions {
+            ecr: self.ecr,
+            local_registry: self.use_local_registry,
+            port_mappings,
+            control_planes: self.control_planes,
+            workers: self.workers,
+            node_labels: self.node_labels,
+            node_taints: self.node_taints,
+            k8s_version: self.k8s_version,
+            node_images: self.node_images,
+            metadata: self.metadata,
+            verbose: self.verbose,
+            timeout: self.timeout,
+            no_wait: self.no_wait,
+        })
+    }
 }
 
+#[derive(StructOpt, Debug)]
+enum MatrixOpt {
+    /// Creates one cluster per Kubernetes version, in parallel
+    Create {
+        /// Kubernetes versions, like 1.25,1.26,1.27
+        #[structopt(long, required = true, use_delimiter = true)]
+        versions: Vec<String>,
+
+        /// Prefix of the cluster names, clusters are named like <prefix>-1-27
+        #[structopt(long, default_value = "hake")]
+        prefix: String,
+
+        /// Cluster spec file used for every cluster
+        #[structopt(long, short)]
+        file: Option<String>,
+
+        /// Provider [default: kind]
+        #[structopt(long)]
+        provider: Option<String>,
+    },
+    /// Deletes the clusters created with matrix create
+    Delete {
+        /// Kubernetes versions, like 1.25,1.26,1.27
+        #[structopt(long, required = true, use_delimiter = true)]
+        versions: Vec<String>,
+
+        /// Prefix of the cluster names
+        #[structopt(long, default_value = "hake")]
+        prefix: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum AddonsOpt {
+    /// Lists the known addons and their versions
+    List,
+    /// Lists the addons installed into a cluster
+    Installed {
+        /// Name of the cluster
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        cluster: String,
+    },
+    /// Downloads addons into ~/.hake/cache, to install them offline
+    Fetch {
+        /// Names of the addons [default: every addon]
+        names: Vec<String>,
+
+        /// Version of the addons, like 1.12 [default: the addon's default]
+        #[structopt(long)]
+        version: Option<String>,
+
+        /// Also save the images of the addons, to load them into kind clusters
+        #[structopt(long)]
+        images: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum RegistryOpt {
+    /// Starts the registry, creating it if needed. Push images to localhost:5000
+    Create,
+    /// Removes the registry
+    Delete {
+        /// Also remove the volume with the images
+        #[structopt(long)]
+        volume: bool,
+    },
+    /// Shows the state of the registry
+    Status,
+}
+
+fn load_spec(file: Option<String>) -> Result<ClusterSpec> {
+    match file {
+        Some(file) => ClusterSpec::from_file(&file),
+        None => Ok(ClusterSpec::default()),
+    }
+}
+
+/// Prints the progress reported by hake.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info && metadata.target().starts_with("hake")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
 fn create(
     name: String,
     provider: String,
-    ecr: Option<String>,
-    use_local_registry: Option<String>,
-    extra_port_mapping: Option<String>,
-    metadata: Option<String>,
-    verbose: bool,
+    options: CreateOptions,
+    addons: Vec<String>,
 ) -> Result<()> {
-    let cluster_dir = format!("{}/{}", get_config_dir(), name);
-    if Path::new(&cluster_dir).exists() {
+    if state::exists(&name) {
         println!("Cluster with name {} already exists", name);
         return Ok(());
     }
@@ -106,145 +319,247 @@ fn create(
     let cyan = Style::new().cyan();
     println!("Creating cluster: {}", cyan.apply_to(&name));
 
-    match &provider[..] {
-        "digitalocean" | "do" => r#do::create(&name, metadata),
-        "kind" => {
-            let mut cluster = Kind::new(&name);
-            cluster.configure_private_registry(ecr);
-
-            if let Some(container_name) = use_local_registry {
-                cluster.use_local_registry(&container_name)
-            }
-            if let Some(extra_port_mapping) = extra_port_mapping {
-                cluster.extra_port_mapping(&extra_port_mapping);
-            }
-            cluster.set_verbose(verbose);
-
-            cluster.create()
-        }
-        _ => Ok(()),
-    }
+    hake::create(&name, &provider, options)?;
+    hake::install_addons(&name, &addons)
 }
 
-fn recreate(name: &str) -> Result<()> {
+fn recreate(name: &str, overrides: CreateOptions) -> Result<()> {
     let cyan = Style::new().cyan();
     println!("Recreating cluster: {}", cyan.apply_to(name));
 
-    Kind::recreate(name, false)
+    hake::recreate(name, overrides)?;
+
+    Ok(())
 }
 
-fn get_config_dir() -> String {
-    let home = String::from(
-        dirs::home_dir()
-            .expect("User does not have a home")
-            .to_str()
-            .expect("User does not have a home"),
-    );
+fn delete(name: &str) -> Result<()> {
+    let cyan = Style::new().cyan();
+    println!("Deleting cluster: {}", cyan.apply_to(name));
 
-    format!("{}/.hake", home)
+    hake::delete(name)
 }
 
-fn cluster_type(name: &str) -> ClusterType {
-    let config_dir = get_config_dir();
-    let cluster_dir = format!("{}/{}", config_dir, name);
+fn config(name: &str) -> Result<()> {
+    println!("export KUBECONFIG={}", hake::kubeconfig(name)?);
 
-    if Path::new(&format!("{}/cluster_uuid", cluster_dir)).exists() {
-        ClusterType::DigitalOcean
-    } else {
-        ClusterType::Kind
-    }
+    Ok(())
 }
 
-fn delete(name: String) -> Result<()> {
-    let cyan = Style::new().cyan();
-    println!("Deleting cluster: {}", cyan.apply_to(&name));
-    match cluster_type(&name) {
-        ClusterType::Kind => {
-            let cluster = Kind::new(&name);
-            cluster.delete()
-        }
-        ClusterType::DigitalOcean => r#do::delete(&name),
+fn list(output: list::OutputFormat) -> Result<()> {
+    let entries = list::entries(&hake::clusters()?);
+
+    match output {
+        list::OutputFormat::Table => print!("{}", list::table(&entries)),
+        list::OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+        list::OutputFormat::Yaml => println!("{}", serde_yaml::to_string(&entries)?),
     }
-}
 
-fn config(name: &str) {
-    println!("export KUBECONFIG={}/{}/kubeconfig", get_config_dir(), name);
+    Ok(())
 }
 
-fn all_clusters() -> Vec<String> {
-    let mut clusters = Vec::new();
-
-    if let Ok(config) = Kind::get_config_dir() {
-        let config = Path::new(&config);
-        for entry in fs::read_dir(config).expect("could not read dir") {
-            let entry = entry.unwrap();
-            let entry = entry.file_name().to_str().unwrap().to_string();
-            clusters.push(entry);
+/// Prints the outcome of every cluster in a matrix, and fails if any of
+/// them failed.
+fn report(results: &[(String, Result<()>)]) -> Result<()> {
+    let green = Style::new().green();
+    let red = Style::new().red();
+
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, result) in results {
+        match result {
+            Ok(_) => println!("{:width$}   {}", name, green.apply_to("ok"), width = width),
+            Err(e) => println!(
+                "{:width$}   {} {}",
+                name,
+                red.apply_to("failed:"),
+                e,
+                width = width
+            ),
         }
     }
 
-    clusters
-}
-
-fn list() {
-    for cluster in all_clusters() {
-        println!("{}", cluster);
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} clusters failed",
+            failed,
+            results.len()
+        ));
     }
+
+    Ok(())
 }
 
-fn add(cap: &str) -> Result<()> {
-    match cap {
-        "cert-manager" => add::cert_manager(),
-        "ingress-nginx" => add::ingress_nginx(),
-        _ => Ok(()),
+fn registry_status() -> Result<()> {
+    let cyan = Style::new().cyan();
+    let status = registry::status()?;
+
+    match status.state {
+        Some(state) => println!(
+            "{}: {}, networks: {}",
+            cyan.apply_to(registry::NAME),
+            state,
+            status.networks.join(", ")
+        ),
+        None => println!("{}: not found", cyan.apply_to(registry::NAME)),
     }
+
+    Ok(())
 }
 
 fn clean(force: bool) -> Result<()> {
-    let kc = Kind::get_kind_containers()?;
-    let clusters = all_clusters();
-
-    for cluster in clusters {
-        if !kc.iter().any(|c| *c == cluster) {
-            let dir = format!("{}/{}", Kind::get_config_dir()?, cluster);
-            if force {
-                println!("Removing {}", dir);
-                fs::remove_dir_all(dir)?
-            } else {
-                println!("Not removing {}. Use --force", dir);
-            }
+    let not_found = hake::not_found_clusters()?;
+    for state in not_found.clusters {
+        if force {
+            println!("Removing {}", state.dir());
+            state.remove()?;
+        } else {
+            println!("Not removing {}. Use --force", state.dir());
         }
     }
 
+    let red = Style::new().red();
+    for (name, e) in &not_found.failed {
+        println!("{} {}: {:#}", red.apply_to("Could not check"), name, e);
+    }
+    if !not_found.failed.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} clusters could not be checked",
+            not_found.failed.len()
+        ));
+    }
+
     Ok(())
 }
 
 fn main() -> Result<()> {
     let matches = Opt::from_args();
+    log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info))?;
 
     match matches {
         Opt::Create {
+            file,
             name,
             provider,
-            ecr,
-            use_local_registry,
-            extra_port_mappings,
-            verbose,
-            metadata,
-        } => create(
-            name,
+            cluster,
+        } => {
+            let spec = load_spec(file)?;
+            let cli = cluster.options()?;
+
+            let mut options = spec.options();
+            options.ecr = cli.ecr.or(options.ecr);
+            options.local_registry = cli.local_registry.or(options.local_registry);
+            options.port_mappings.extend(cli.port_mappings);
+            options.control_planes = cli.control_planes.or(options.control_planes);
+            options.workers = cli.workers.or(options.workers);
+            options.node_labels.extend(cli.node_labels);
+            options.node_taints.extend(cli.node_taints);
+            options.k8s_version = cli.k8s_version.or(options.k8s_version);
+            options.node_images.extend(cli.node_images);
+            // later keys win, so --metadata overrides the spec
+            options.metadata = match (options.metadata, cli.metadata) {
+                (Some(spec), Some(cli)) => Some(format!("{}&{}", spec, cli)),
+                (spec, cli) => cli.or(spec),
+            };
+            options.verbose = cli.verbose;
+            options.timeout = cli.timeout;
+            options.no_wait = cli.no_wait;
+
+            create(
+                name.or(spec.name)
+                    .unwrap_or_else(|| String::from(DEFAULT_NAME)),
+                provider
+                    .or(spec.provider)
+                    .unwrap_or_else(|| String::from(DEFAULT_PROVIDER)),
+                options,
+                spec.addons,
+            )
+        }
+        Opt::Recreate { name, cluster } => recreate(&name, cluster.options()?),
+        Opt::Delete { name } => delete(&name),
+        Opt::Config { name } => config(&name),
+        Opt::List { output } => list(output),
+        Opt::Matrix(MatrixOpt::Create {
+            versions,
+            prefix,
+            file,
             provider,
-            ecr,
-            use_local_registry,
-            extra_port_mappings,
-            metadata,
-            verbose,
+        }) => {
+            let spec = load_spec(file)?;
+            report(&matrix::create(
+                &prefix,
+                &versions,
+                &provider
+                    .or(spec.provider.clone())
+                    .unwrap_or_else(|| String::from(DEFAULT_PROVIDER)),
+                spec.options(),
+                spec.addons,
+            ))
+        }
+        Opt::Matrix(MatrixOpt::Delete { versions, prefix }) => {
+            report(&matrix::delete(&prefix, &versions))
+        }
+        Opt::Registry(RegistryOpt::Create) => {
+            registry::create()?;
+            println!(
+                "Use it with: hake create --use-local-registry {}",
+                registry::NAME
+            );
+
+            Ok(())
+        }
+        Opt::Registry(RegistryOpt::Delete { volume }) => registry::delete(volume),
+        Opt::Registry(RegistryOpt::Status) => registry_status(),
+        Opt::Add {
+            name,
+            cluster,
+            version,
+            offline,
+            timeout,
+            no_wait,
+        } => hake::add(
+            &cluster,
+            &name,
+            &AddOptions {
+                version,
+                offline,
+                timeout,
+                no_wait,
+            },
         ),
-        Opt::Recreate { name } => recreate(&name),
-        Opt::Delete { name } => delete(name),
-        Opt::Config { name } => Ok(config(&name)),
-        Opt::List => Ok(list()),
-        Opt::Add { name } => add(&name),
+        Opt::Addons(AddonsOpt::List) => {
+            print!("{}", addons::table(&addons::catalog()));
+
+            Ok(())
+        }
+        Opt::Remove { name, cluster } => hake::remove(&cluster, &name),
+        Opt::Addons(AddonsOpt::Installed { cluster }) => {
+            print!(
+                "{}",
+                addons::installed_table(&hake::installed_addons(&cluster)?)
+            );
+
+            Ok(())
+        }
+        Opt::Addons(AddonsOpt::Fetch {
+            names,
+            version,
+            images,
+        }) => {
+            let names = if names.is_empty() {
+                addons::names()
+            } else {
+                names
+            };
+            for name in names {
+                hake::fetch(&name, version.as_deref(), images)?;
+            }
+
+            Ok(())
+        }
         Opt::Clean { force } => clean(force),
     }
 }
@@ -0,0 +1,80 @@
+// Creates and deletes one cluster per Kubernetes version, all at once.
+
+use anyhow::{anyhow, Result};
+
+use std::thread;
+use std::vec::Vec;
+
+use crate::provider::CreateOptions;
+
+/// Name of the cluster for `version` in a matrix, like `ci-1-27`.
+fn cluster_name(prefix: &str, version: &str) -> String {
+    format!(
+        "{}-{}",
+        prefix,
+        version.trim_start_matches('v').replace('.', "-")
+    )
+}
+
+/// Runs `action` for every version in its own thread. A failure in one
+/// cluster doesn't stop the others.
+fn run<F>(prefix: &str, versions: &[String], action: F) -> Vec<(String, Result<()>)>
+where
+    F: Fn(String, String) -> Result<()> + Send + Sync + Clone + 'static,
+{
+    let handles: Vec<(String, thread::JoinHandle<Result<()>>)> = versions
+        .iter()
+        .map(|version| {
+            let name = cluster_name(prefix, version);
+            let (thread_name, version) = (name.clone(), version.clone());
+            let action = action.clone();
+
+            (name, thread::spawn(move || action(thread_name, version)))
+        })
+        .collect();
+
+    handles
+        .into_iter()
+        .map(|(name, handle)| {
+            let result = handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Thread for cluster {} panicked", name)));
+            (name, result)
+        })
+        .collect()
+}
+
+/// Creates a cluster per version, returning the outcome for every cluster.
+pub fn create(
+    prefix: &str,
+    versions: &[String],
+    provider: &str,
+    options: CreateOptions,
+    addons: Vec<String>,
+) -> Vec<(String, Result<()>)> {
+    let provider = String::from(provider);
+    run(prefix, versions, move |name, version| {
+        let mut options = options.clone();
+        options.k8s_version = Some(version);
+
+        crate::create(&name, &provider, options)?;
+        crate::install_addons(&name, &addons)
+    })
+}
+
+/// Deletes the cluster of every version, returning the outcome for every
+/// cluster.
+pub fn delete(prefix: &str, versions: &[String]) -> Vec<(String, Result<()>)> {
+    run(prefix, versions, |name, _| crate::delete(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matrix;
+
+    #[test]
+    fn test_cluster_name() {
+        assert_eq!(matrix::cluster_name("ci", "1.27"), "ci-1-27");
+        assert_eq!(matrix::cluster_name("ci", "v1.27.3"), "ci-1-27-3");
+    }
+}
@@ -0,0 +1,348 @@
+#![allow(non_snake_case)]
+
+// Clusters created with minikube using the docker driver, one minikube
+// profile per cluster.
+
+use anyhow::{anyhow, Result};
+use serde_derive::Deserialize;
+
+use std::vec::Vec;
+
+use regex::Regex;
+
+use crate::cmd::{self, CommandError};
+use crate::ports;
+use crate::provider::{self, ClusterInfo, CreateOptions, Provider, Status};
+use crate::registry;
+use crate::state::ClusterState;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct KubernetesConfig {
+    KubernetesVersion: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ProfileConfig {
+    KubernetesConfig: KubernetesConfig,
+    Nodes: Vec<serde_json::Value>,
+}
+
+/// A profile in the output of `minikube profile list -o json`.
+#[derive(Deserialize, Debug)]
+struct Profile {
+    Name: String,
+    #[serde(default)]
+    Status: String,
+    #[serde(default)]
+    Config: ProfileConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Profiles {
+    valid: Vec<Profile>,
+    invalid: Vec<Profile>,
+}
+
+/// Options hake has that minikube profiles don't support.
+fn unsupported(options: &CreateOptions) -> Vec<&'static str> {
+    let mut unsupported = vec![];
+    if options.control_planes.unwrap_or(1) != 1 {
+        unsupported.push("multiple control planes");
+    }
+    unsupported.extend(provider::node_options(options));
+    if options.ecr.is_some() {
+        unsupported.push("ECR registries");
+    }
+
+    unsupported
+}
+
+/// minikube needs full versions, like `v1.27.4`.
+fn kubernetes_version(version: &str) -> Result<String> {
+    let version = version.trim_start_matches('v');
+    let re = Regex::new(r"^\d+\.\d+\.\d+$").unwrap();
+    if !re.is_match(version) {
+        return Err(anyhow!(
+            "The minikube provider needs a full Kubernetes version like 1.27.4, got {}",
+            version
+        ));
+    }
+
+    Ok(format!("v{}", version))
+}
+
+/// Port mappings are published on the node container, using the docker
+/// syntax.
+fn minikube_ports(mappings: &[String]) -> Result<Vec<String>> {
+    Ok(ports::parse_all(mappings)?
+        .into_iter()
+        .map(|pm| {
+            let protocol = pm.protocol.as_str().to_lowercase();
+            match &pm.listen_address {
+                Some(address) => format!(
+                    "{}:{}:{}/{}",
+                    address, pm.host_port, pm.container_port, protocol
+                ),
+                None => format!("{}:{}/{}", pm.host_port, pm.container_port, protocol),
+            }
+        })
+        .collect())
+}
+
+/// Arguments of `minikube start`. The local registry is reached by its
+/// container name, like `hake-registry:5000`, as minikube has no way of
+/// mirroring `localhost:5000`.
+fn start_args(state: &ClusterState) -> Result<Vec<String>> {
+    let options = &state.options;
+    let nodes = options.control_planes.unwrap_or(1) + options.workers.unwrap_or(0);
+
+    let mut args: Vec<String> = vec![
+        "start",
+        "--profile",
+        &state.name,
+        "--driver",
+        "docker",
+        "--nodes",
+        &nodes.to_string(),
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+
+    if let Some(version) = &options.k8s_version {
+        args.push(String::from("--kubernetes-version"));
+        args.push(kubernetes_version(version)?);
+    }
+    for port in minikube_ports(&options.port_mappings)? {
+        args.push(String::from("--ports"));
+        args.push(port);
+    }
+    if let Some(container_name) = &options.local_registry {
+        args.push(String::from("--insecure-registry"));
+        args.push(format!("{}:5000", container_name));
+    }
+
+    Ok(args)
+}
+
+/// minikube writes the cluster credentials to `$KUBECONFIG`, which is kept
+/// in the cluster directory instead of `~/.kube/config`.
+fn kubeconfig(state: &ClusterState) -> String {
+    format!("{}/kubeconfig", state.dir())
+}
+
+fn minikube(state: &ClusterState, args: &[&str]) -> Result<()> {
+    let kubeconfig = kubeconfig(state);
+    let env = [("KUBECONFIG", &kubeconfig[..])];
+
+    if state.options.verbose {
+        cmd::run_attached_with_env("minikube", args, &env)
+    } else {
+        cmd::run_with_env("minikube", args, &env).map(|_| ())
+    }
+}
+
+fn create_cluster(state: &ClusterState) -> Result<()> {
+    if let Some(container_name) = &state.options.local_registry {
+        registry::check(container_name)?;
+    }
+    let args = start_args(state)?;
+    minikube(state, &args.iter().map(|a| &a[..]).collect::<Vec<&str>>())?;
+    // minikube puts the nodes in a network named after the profile
+    if let Some(container_name) = &state.options.local_registry {
+        registry::join(container_name, &state.name)?;
+    }
+
+    provider::wait_until_ready(state, &kubeconfig(state))
+}
+
+fn list_profiles() -> Result<Vec<Profile>> {
+    let output = match cmd::run("minikube", &["profile", "list", "-o", "json"]) {
+        Ok(output) => output,
+        Err(e) => match e.downcast_ref::<CommandError>() {
+            // minikube fails when there are no profiles at all
+            Some(err) if err.reason().contains("No minikube profile") => return Ok(vec![]),
+            _ => return Err(e),
+        },
+    };
+    let profiles: Profiles = serde_json::from_str(&output)?;
+
+    Ok(profiles.valid.into_iter().chain(profiles.invalid).collect())
+}
+
+fn cluster_info(profile: &Profile) -> ClusterInfo {
+    let version = &profile.Config.KubernetesConfig.KubernetesVersion;
+
+    ClusterInfo {
+        status: match &profile.Status[..] {
+            "Running" => Status::Running,
+            "" => Status::Provisioning(String::from("unknown")),
+            status => Status::Provisioning(String::from(status)),
+        },
+        version: if version.is_empty() {
+            None
+        } else {
+            Some(version.clone())
+        },
+        nodes: Some(profile.Config.Nodes.len()),
+    }
+}
+
+pub struct Minikube;
+
+impl Provider for Minikube {
+    fn name(&self) -> &str {
+        "minikube"
+    }
+
+    fn validate(&self, _name: &str, options: &CreateOptions) -> Result<()> {
+        provider::reject_unsupported(self.name(), &unsupported(options))?;
+        if let Some(version) = &options.k8s_version {
+            kubernetes_version(version)?;
+        }
+        minikube_ports(&options.port_mappings)?;
+
+        Ok(())
+    }
+
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        ports::check_available(&ports::parse_all(&state.options.port_mappings)?)?;
+
+        create_cluster(state)
+    }
+
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        minikube(state, &["delete", "--profile", &state.name])
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        Ok(self.info(state)?.status)
+    }
+
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        Ok(list_profiles()?
+            .iter()
+            .find(|p| p.Name == state.name)
+            .map(cluster_info)
+            .unwrap_or_else(|| ClusterInfo::from(Status::NotFound)))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        Ok(list_profiles()?.into_iter().map(|p| p.Name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd;
+    use crate::minikube::{self, Minikube, Profiles};
+    use crate::provider::{CreateOptions, Provider, Status};
+    use crate::state::ClusterState;
+    use crate::testing::{self, Env, FakeRunner};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_start_args() {
+        let mut env = Env::new();
+        env.scratch_home("minikube-args");
+        let options = CreateOptions {
+            workers: Some(2),
+            port_mappings: vec![String::from("8080:80"), String::from("127.0.0.1:53:53/udp")],
+            k8s_version: Some(String::from("1.27.4")),
+            local_registry: Some(String::from("hake-registry")),
+            ..Default::default()
+        };
+        let state = ClusterState::new("tests", "minikube", options).unwrap();
+
+        assert_eq!(
+            minikube::start_args(&state).unwrap().join(" "),
+            "start --profile tests --driver docker --nodes 3 --kubernetes-version v1.27.4 \
+             --ports 8080:80/tcp --ports 127.0.0.1:53:53/udp \
+             --insecure-registry hake-registry:5000"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let options = CreateOptions {
+            control_planes: Some(3),
+            ecr: Some(String::from("1234.dkr.ecr.eu-west-1.amazonaws.com")),
+            ..Default::default()
+        };
+        assert_eq!(
+            Minikube
+                .validate("tests", &options)
+                .err()
+                .unwrap()
+                .to_string(),
+            "The minikube provider does not support multiple control planes, ECR registries"
+        );
+
+        let options = CreateOptions {
+            k8s_version: Some(String::from("1.27")),
+            ..Default::default()
+        };
+        assert_eq!(
+            Minikube
+                .validate("tests", &options)
+                .err()
+                .unwrap()
+                .to_string(),
+            "The minikube provider needs a full Kubernetes version like 1.27.4, got 1.27"
+        );
+    }
+
+    #[test]
+    fn test_cluster_info() {
+        let profiles: Profiles = serde_json::from_str(
+            r#"{"invalid": [], "valid": [
+                {"Name": "tests", "Status": "Running", "Config": {
+                    "KubernetesConfig": {"KubernetesVersion": "v1.27.4"},
+                    "Nodes": [{"Name": ""}, {"Name": "m02"}]}},
+                {"Name": "stopped", "Status": "Stopped", "Config": {}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let info = minikube::cluster_info(&profiles.valid[0]);
+        assert_eq!(info.status, Status::Running);
+        assert_eq!(info.version, Some(String::from("v1.27.4")));
+        assert_eq!(info.nodes, Some(2));
+
+        let info = minikube::cluster_info(&profiles.valid[1]);
+        assert_eq!(info.status, Status::Provisioning(String::from("Stopped")));
+        assert_eq!(info.version, None);
+    }
+
+    #[test]
+    fn test_flow() {
+        let mut env = Env::new();
+        let home = env.scratch_home("minikube-flow");
+        let runner = Rc::new(FakeRunner::new());
+
+        let options = CreateOptions {
+            no_wait: true,
+            ..Default::default()
+        };
+        cmd::with_runner(runner.clone(), || {
+            testing::cluster_flow("mini", "minikube", options, |_| {})
+        });
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!(
+                    "KUBECONFIG={}/mini/kubeconfig minikube start --profile mini --driver docker --nodes 1",
+                    home
+                ),
+                format!(
+                    "KUBECONFIG={}/mini/kubeconfig minikube delete --profile mini",
+                    home
+                ),
+            ]
+        );
+    }
+}
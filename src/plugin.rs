@@ -0,0 +1,324 @@
+// Providers implemented outside of hake, as `hake-provider-<name>`
+// executables found in $PATH.
+//
+// hake runs the plugin with the action as its only argument and writes a
+// JSON request to its stdin, like:
+//
+//   {"protocol": 1, "action": "create", "name": "tests",
+//    "dir": "/home/user/.hake/tests", "remote_id": null,
+//    "timeout_seconds": 600, "wait": true, "options": {...}}
+//
+// The plugin answers with a JSON object on stdout. Every field is optional:
+//
+//   {"error": "...", "remote_id": "...", "kubeconfig": "...",
+//    "status": "running|provisioning|not_found", "message": "...",
+//    "version": "1.27.4", "nodes": 3, "clusters": ["tests"]}
+//
+// A non-zero exit code or an `error` field fails the action, and plugins
+// that don't answer in time are killed.
+
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::cmd;
+use crate::provider::{ClusterInfo, CreateOptions, Provider, Status};
+use crate::state::ClusterState;
+
+/// Version of the protocol, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const PREFIX: &str = "hake-provider-";
+
+/// How long a plugin gets to answer, on top of the timeout it was given
+/// for creating or deleting a cluster.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a plugin gets to create or delete a cluster when no timeout is
+/// given.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Debug)]
+struct Request<'a> {
+    protocol: u32,
+    action: &'a str,
+    name: &'a str,
+    dir: String,
+    remote_id: Option<&'a str>,
+    timeout_seconds: Option<u64>,
+    wait: bool,
+    options: &'a CreateOptions,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Response {
+    error: Option<String>,
+    remote_id: Option<String>,
+    kubeconfig: Option<String>,
+    status: Option<String>,
+    message: Option<String>,
+    version: Option<String>,
+    nodes: Option<usize>,
+    clusters: Vec<String>,
+}
+
+#[cfg(unix)]
+fn is_executable(path: &PathBuf) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &PathBuf) -> bool {
+    path.is_file()
+}
+
+fn search_path() -> Vec<PathBuf> {
+    #[cfg(test)]
+    crate::testing::assert_env_locked("PATH");
+
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the plugin providing `name`, the first one in $PATH wins.
+pub fn find(name: &str) -> Option<Plugin> {
+    search_path()
+        .into_iter()
+        .map(|dir| dir.join(format!("{}{}", PREFIX, name)))
+        .find(is_executable)
+        .map(|path| Plugin {
+            name: String::from(name),
+            path,
+        })
+}
+
+/// Names of the plugins in $PATH.
+pub fn discover() -> Vec<String> {
+    let mut names: Vec<String> = search_path()
+        .into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(|e| e.ok()))
+        .filter(|entry| is_executable(&entry.path()))
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|f| f.strip_prefix(PREFIX))
+                .map(String::from)
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+}
+
+pub struct Plugin {
+    name: String,
+    path: PathBuf,
+}
+
+impl Plugin {
+    fn call(&self, action: &str, state: &ClusterState) -> Result<Response> {
+        let request = Request {
+            protocol: PROTOCOL_VERSION,
+            action,
+            name: &state.name,
+            dir: state.dir(),
+            remote_id: state.remote_id.as_deref(),
+            timeout_seconds: state.options.timeout.map(|t| t.as_secs()),
+            wait: !state.options.no_wait,
+            options: &state.options,
+        };
+
+        // a plugin that hangs would otherwise hang hake
+        let timeout = match action {
+            "create" | "delete" => match state.options.timeout {
+                Some(timeout) => timeout + ANSWER_TIMEOUT,
+                None => DEFAULT_TIMEOUT,
+            },
+            _ => ANSWER_TIMEOUT,
+        };
+
+        let program = self.path.to_string_lossy();
+        let output = cmd::run_with_timeout(
+            &program,
+            &[action],
+            &serde_json::to_string(&request)?,
+            timeout,
+        )?;
+        let response: Response = serde_json::from_str(&output)
+            .with_context(|| format!("Invalid response from {}", program))?;
+
+        match response.error {
+            Some(error) => Err(anyhow!("{} {} failed: {}", program, action, error)),
+            None => Ok(response),
+        }
+    }
+
+    fn save_kubeconfig(state: &ClusterState, response: &Response) -> Result<()> {
+        if let Some(kubeconfig) = &response.kubeconfig {
+            File::create(format!("{}/kubeconfig", state.dir()))?
+                .write_all(kubeconfig.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Provider for Plugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn create(&self, state: &mut ClusterState) -> Result<()> {
+        let response = self.call("create", state)?;
+        if response.remote_id.is_some() {
+            state.remote_id = response.remote_id.clone();
+            state.save()?;
+        }
+
+        Plugin::save_kubeconfig(state, &response)
+    }
+
+    fn delete(&self, state: &ClusterState) -> Result<()> {
+        self.call("delete", state)?;
+
+        Ok(())
+    }
+
+    /// The plugin is asked for the kubeconfig, which is kept in the
+    /// cluster directory.
+    fn kubeconfig(&self, state: &ClusterState) -> Result<String> {
+        let response = self.call("kubeconfig", state)?;
+        if response.kubeconfig.is_none() {
+            return Err(anyhow!(
+                "{} did not return a kubeconfig",
+                self.path.to_string_lossy()
+            ));
+        }
+        Plugin::save_kubeconfig(state, &response)?;
+
+        Ok(format!("{}/kubeconfig", state.dir()))
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status> {
+        Ok(self.info(state)?.status)
+    }
+
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        let response = self.call("status", state)?;
+        let status = match response.status.as_deref() {
+            Some("running") => Status::Running,
+            Some("not_found") => Status::NotFound,
+            Some("provisioning") => Status::Provisioning(
+                response
+                    .message
+                    .unwrap_or_else(|| String::from("provisioning")),
+            ),
+            Some(other) => {
+                return Err(anyhow!(
+                    "Invalid status from {}: {}",
+                    self.path.to_string_lossy(),
+                    other
+                ))
+            }
+            None => Status::Provisioning(String::from("unknown")),
+        };
+
+        Ok(ClusterInfo {
+            status,
+            version: response.version,
+            nodes: response.nodes,
+        })
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let state = ClusterState::new("", &self.name, CreateOptions::default())?;
+
+        Ok(self.call("list", &state)?.clusters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin;
+    use crate::provider::{self, CreateOptions, Status};
+    use crate::testing::{self, Env};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Answers every action, recording the requests in requests.log
+    const PLUGIN: &str = r#"#!/bin/sh
+cat >> "$(dirname "$0")/requests.log"
+echo >> "$(dirname "$0")/requests.log"
+case "$1" in
+  create) echo '{"remote_id": "rack-1", "kubeconfig": "apiVersion: v1"}' ;;
+  status) echo '{"status": "running", "version": "1.27.4", "nodes": 2}' ;;
+  delete) echo '{}' ;;
+  *) echo '{"error": "unsupported action"}' ;;
+esac
+"#;
+
+    #[test]
+    fn test_plugin_flow() {
+        let mut env = Env::new();
+        let home = env.scratch_home("plugin-flow");
+        let bin = format!("{}/bin", home);
+        fs::create_dir_all(&bin).unwrap();
+        let path = format!("{}/hake-provider-rack", bin);
+        fs::write(&path, PLUGIN).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        env.set(
+            "PATH",
+            &format!("{}:{}", bin, std::env::var("PATH").unwrap_or_default()),
+        );
+
+        assert!(plugin::discover().contains(&String::from("rack")));
+        assert_eq!(provider::get("rack").unwrap().name(), "rack");
+
+        testing::cluster_flow("metal", "rack", CreateOptions::default(), |state| {
+            assert_eq!(state.provider, "rack");
+            assert_eq!(state.remote_id, Some(String::from("rack-1")));
+            assert_eq!(
+                fs::read_to_string(format!("{}/kubeconfig", state.dir())).unwrap(),
+                "apiVersion: v1"
+            );
+
+            let info = provider::get("rack").unwrap().info(state).unwrap();
+            assert_eq!(info.status, Status::Running);
+            assert_eq!(info.nodes, Some(2));
+
+            assert_eq!(
+                crate::kubeconfig("metal").err().unwrap().to_string(),
+                format!("{} kubeconfig failed: unsupported action", path)
+            );
+        });
+
+        let requests = fs::read_to_string(format!("{}/requests.log", bin)).unwrap();
+        let requests: Vec<serde_json::Value> = requests
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let actions: Vec<&str> = requests
+            .iter()
+            .map(|r| r["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(actions, vec!["create", "status", "kubeconfig", "delete"]);
+        assert_eq!(requests[0]["dir"], format!("{}/metal", home));
+        assert_eq!(requests[0]["protocol"], 1);
+        assert_eq!(requests[3]["remote_id"], "rack-1");
+    }
+}
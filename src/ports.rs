@@ -0,0 +1,299 @@
+// Port mappings from the host into the cluster nodes, written like
+// `[listenAddress:]hostPort:containerPort[/protocol]`.
+
+use anyhow::{anyhow, Result};
+
+use std::net::{TcpListener, UdpSocket};
+use std::vec::Vec;
+
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl Protocol {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+            Protocol::Sctp => "SCTP",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortMapping {
+    pub listen_address: Option<String>,
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl PortMapping {
+    /// Two mappings conflict if they would listen on the same host port.
+    fn conflicts_with(&self, other: &PortMapping) -> bool {
+        let any =
+            |addr: &Option<String>| matches!(addr.as_deref(), None | Some("0.0.0.0") | Some("::"));
+
+        self.protocol == other.protocol
+            && self.host_port == other.host_port
+            && (any(&self.listen_address)
+                || any(&other.listen_address)
+                || self.listen_address == other.listen_address)
+    }
+}
+
+/// Parses `80`, `8080-8090` or `80-90` style ports, returning the first and
+/// last port of the range.
+fn parse_range(range: &str, mapping: &str) -> Result<(u16, u16)> {
+    let invalid = || anyhow!("Invalid port {} in port mapping {}", range, mapping);
+
+    let mut parts = range.splitn(2, '-');
+    let first = parts
+        .next()
+        .and_then(|p| p.parse::<u16>().ok())
+        .filter(|p| *p != 0)
+        .ok_or_else(invalid)?;
+    let last = match parts.next() {
+        Some(p) => p
+            .parse::<u16>()
+            .ok()
+            .filter(|p| *p >= first)
+            .ok_or_else(invalid)?,
+        None => first,
+    };
+
+    Ok((first, last))
+}
+
+/// Parses a single port mapping, which expands to one mapping per port when
+/// ranges are used.
+pub fn parse(mapping: &str) -> Result<Vec<PortMapping>> {
+    let re = Regex::new(
+        r"^(?:(\[[0-9a-fA-F:.]+\]|\d{1,3}(?:\.\d{1,3}){3}):)?(?:([0-9-]+):)?([0-9-]+)(?:/([a-zA-Z]+))?$",
+    )
+    .unwrap();
+    let cap = re.captures(mapping).ok_or_else(|| {
+        anyhow!(
+            "Invalid port mapping {}, expected [listenAddress:]hostPort:containerPort[/protocol]",
+            mapping
+        )
+    })?;
+
+    let listen_address = cap.get(1).map(|a| {
+        a.as_str()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string()
+    });
+    let protocol = match cap.get(4).map(|p| p.as_str().to_uppercase()).as_deref() {
+        None | Some("TCP") => Protocol::Tcp,
+        Some("UDP") => Protocol::Udp,
+        Some("SCTP") => Protocol::Sctp,
+        Some(p) => {
+            return Err(anyhow!(
+                "Invalid protocol {} in port mapping {}, expected TCP, UDP or SCTP",
+                p,
+                mapping
+            ))
+        }
+    };
+
+    let container = parse_range(&cap[3], mapping)?;
+    let host = match cap.get(2) {
+        Some(host) => parse_range(host.as_str(), mapping)?,
+        None => container,
+    };
+    if host.1 - host.0 != container.1 - container.0 {
+        return Err(anyhow!(
+            "Port ranges in port mapping {} have different sizes",
+            mapping
+        ));
+    }
+
+    Ok((0..=(host.1 - host.0))
+        .map(|i| PortMapping {
+            listen_address: listen_address.clone(),
+            host_port: host.0 + i,
+            container_port: container.0 + i,
+            protocol,
+        })
+        .collect())
+}
+
+/// Converts a mapping written for the old `--extra-port-mappings` option,
+/// like `containerPort:hostPort[:TCP]` or `port`, to the current format.
+pub fn from_legacy(mapping: &str) -> Result<String> {
+    let re = Regex::new(r"^(\d+)(?::(\d+)(?::(?:TCP|HTTP))?)?$").unwrap();
+    let cap = re.captures(mapping).ok_or_else(|| {
+        anyhow!(
+            "Invalid port mapping {}, expected containerPort:hostPort[:TCP]",
+            mapping
+        )
+    })?;
+
+    let container = &cap[1];
+    let host = cap.get(2).map(|h| h.as_str()).unwrap_or(container);
+
+    Ok(format!("{}:{}", host, container))
+}
+
+/// Parses every mapping, failing if two of them use the same host port.
+pub fn parse_all(mappings: &[String]) -> Result<Vec<PortMapping>> {
+    let mut parsed: Vec<PortMapping> = vec![];
+    for mapping in mappings {
+        for pm in parse(mapping)? {
+            if parsed.iter().any(|p| p.conflicts_with(&pm)) {
+                return Err(anyhow!(
+                    "Host port {}/{} is mapped more than once",
+                    pm.host_port,
+                    pm.protocol.as_str()
+                ));
+            }
+            parsed.push(pm);
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Fails if any of the host ports is already in use. SCTP ports can't be
+/// checked.
+pub fn check_available(mappings: &[PortMapping]) -> Result<()> {
+    for pm in mappings {
+        let address = pm.listen_address.as_deref().unwrap_or("0.0.0.0");
+        let address = if address.contains(':') {
+            format!("[{}]:{}", address, pm.host_port)
+        } else {
+            format!("{}:{}", address, pm.host_port)
+        };
+
+        let available = match pm.protocol {
+            Protocol::Tcp => TcpListener::bind(&address).is_ok(),
+            Protocol::Udp => UdpSocket::bind(&address).is_ok(),
+            Protocol::Sctp => true,
+        };
+        if !available {
+            return Err(anyhow!(
+                "Host port {}/{} is already in use",
+                address,
+                pm.protocol.as_str()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ports::{self, PortMapping, Protocol};
+    use std::net::TcpListener;
+
+    fn pm(
+        listen_address: Option<&str>,
+        host: u16,
+        container: u16,
+        protocol: Protocol,
+    ) -> PortMapping {
+        PortMapping {
+            listen_address: listen_address.map(String::from),
+            host_port: host,
+            container_port: container,
+            protocol,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ports::parse("80").unwrap(),
+            vec![pm(None, 80, 80, Protocol::Tcp)]
+        );
+        assert_eq!(
+            ports::parse("8080:80").unwrap(),
+            vec![pm(None, 8080, 80, Protocol::Tcp)]
+        );
+        assert_eq!(
+            ports::parse("127.0.0.1:5353:53/udp").unwrap(),
+            vec![pm(Some("127.0.0.1"), 5353, 53, Protocol::Udp)]
+        );
+        assert_eq!(
+            ports::parse("[::1]:9000:9000/SCTP").unwrap(),
+            vec![pm(Some("::1"), 9000, 9000, Protocol::Sctp)]
+        );
+        assert_eq!(
+            ports::parse("30000-30002:31000-31002").unwrap(),
+            vec![
+                pm(None, 30000, 31000, Protocol::Tcp),
+                pm(None, 30001, 31001, Protocol::Tcp),
+                pm(None, 30002, 31002, Protocol::Tcp),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            ports::parse("80:80:TCP").err().unwrap().to_string(),
+            "Invalid port mapping 80:80:TCP, expected [listenAddress:]hostPort:containerPort[/protocol]"
+        );
+        assert_eq!(
+            ports::parse("80/http").err().unwrap().to_string(),
+            "Invalid protocol HTTP in port mapping 80/http, expected TCP, UDP or SCTP"
+        );
+        assert_eq!(
+            ports::parse("8080-8081:80").err().unwrap().to_string(),
+            "Port ranges in port mapping 8080-8081:80 have different sizes"
+        );
+        assert!(ports::parse("70000").is_err());
+        assert!(ports::parse("0").is_err());
+        assert!(ports::parse("90-80").is_err());
+    }
+
+    #[test]
+    fn test_from_legacy() {
+        assert_eq!(ports::from_legacy("80:8080").unwrap(), "8080:80");
+        assert_eq!(ports::from_legacy("80:80:TCP").unwrap(), "80:80");
+        assert_eq!(ports::from_legacy("443:8443:HTTP").unwrap(), "8443:443");
+        assert_eq!(ports::from_legacy("8080").unwrap(), "8080:8080");
+        assert_eq!(
+            ports::from_legacy("80:80/udp").err().unwrap().to_string(),
+            "Invalid port mapping 80:80/udp, expected containerPort:hostPort[:TCP]"
+        );
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let mappings = |m: &[&str]| m.iter().map(|s| String::from(*s)).collect::<Vec<String>>();
+
+        assert_eq!(
+            ports::parse_all(&mappings(&["80", "443", "53/udp", "53/tcp"]))
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(
+            ports::parse_all(&mappings(&["8080:80", "8079-8081:90-92"]))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Host port 8080/TCP is mapped more than once"
+        );
+        assert!(ports::parse_all(&mappings(&["127.0.0.1:80:80", "80:81"])).is_err());
+        assert!(ports::parse_all(&mappings(&["127.0.0.1:80:80", "127.0.0.2:80:81"])).is_ok());
+    }
+
+    #[test]
+    fn test_check_available() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(ports::check_available(&[pm(Some("127.0.0.1"), port, 80, Protocol::Tcp)]).is_err());
+        drop(listener);
+        assert!(ports::check_available(&[pm(Some("127.0.0.1"), port, 80, Protocol::Tcp)]).is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+// Cluster providers and the registry used to look them up by name.
+
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::k3d::K3d;
+use crate::kind::KindProvider;
+use crate::linode::Linode;
+use crate::minikube::Minikube;
+use crate::plugin;
+use crate::r#do::DigitalOcean;
+use crate::ready;
+use crate::state::ClusterState;
+use crate::wait;
+
+/// How long to wait for the nodes of clusters running in docker to be ready
+/// when no timeout is given.
+pub const LOCAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Options used when creating a new cluster. Not every provider uses every
+/// option.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct CreateOptions {
+    pub ecr: Option<String>,
+    pub local_registry: Option<String>,
+    pub port_mappings: Vec<String>,
+    pub control_planes: Option<u32>,
+    pub workers: Option<u32>,
+    pub node_labels: Vec<String>,
+    pub node_taints: Vec<String>,
+    /// Kubernetes version, like `1.27`.
+    pub k8s_version: Option<String>,
+    pub node_images: Vec<String>,
+    pub metadata: Option<String>,
+    #[serde(skip)]
+    pub verbose: bool,
+    /// How long to wait for the cluster to be ready, the provider picks a
+    /// default if not set.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+    /// Return as soon as the cluster has been requested.
+    #[serde(skip)]
+    pub no_wait: bool,
+}
+
+impl CreateOptions {
+    /// Replaces the options that are set in `overrides`, used to change a
+    /// cluster when recreating it. Lists replace the stored ones when not
+    /// empty.
+    pub fn override_with(&mut self, overrides: CreateOptions) {
+        fn list(stored: &mut Vec<String>, overrides: Vec<String>) {
+            if !overrides.is_empty() {
+                *stored = overrides;
+            }
+        }
+
+        self.ecr = overrides.ecr.or_else(|| self.ecr.take());
+        self.local_registry = overrides
+            .local_registry
+            .or_else(|| self.local_registry.take());
+        list(&mut self.port_mappings, overrides.port_mappings);
+        self.control_planes = overrides.control_planes.or(self.control_planes);
+        self.workers = overrides.workers.or(self.workers);
+        list(&mut self.node_labels, overrides.node_labels);
+        list(&mut self.node_taints, overrides.node_taints);
+        self.k8s_version = overrides.k8s_version.or_else(|| self.k8s_version.take());
+        list(&mut self.node_images, overrides.node_images);
+        self.metadata = overrides.metadata.or_else(|| self.metadata.take());
+        self.verbose = overrides.verbose;
+        self.timeout = overrides.timeout;
+        self.no_wait = overrides.no_wait;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Provisioning(String),
+    NotFound,
+}
+
+/// What a provider can tell about a live cluster.
+#[derive(Debug)]
+pub struct ClusterInfo {
+    pub status: Status,
+    pub version: Option<String>,
+    pub nodes: Option<usize>,
+}
+
+impl From<Status> for ClusterInfo {
+    fn from(status: Status) -> Self {
+        ClusterInfo {
+            status,
+            version: None,
+            nodes: None,
+        }
+    }
+}
+
+pub trait Provider {
+    /// Canonical name of this provider, as stored on disk.
+    fn name(&self) -> &str;
+
+    /// Other names this provider can be selected with.
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Checks the name and the provider specific options of cluster `name`
+    /// before anything is created.
+    fn validate(&self, _name: &str, _options: &CreateOptions) -> Result<()> {
+        Ok(())
+    }
+
+    /// Creates the cluster described by `state`. The cluster directory
+    /// already exists; providers record any remote id in `state`.
+    fn create(&self, state: &mut ClusterState) -> Result<()>;
+
+    /// Deletes the cluster. The cluster directory is removed by the caller.
+    fn delete(&self, state: &ClusterState) -> Result<()>;
+
+    /// Deletes the cluster and creates it again from its stored options, so
+    /// short lived credentials like ECR logins are fetched again.
+    fn recreate(&self, state: &mut ClusterState) -> Result<()> {
+        self.delete(state)?;
+
+        self.create(state)
+    }
+
+    /// Path to the kubeconfig file for this cluster.
+    fn kubeconfig(&self, state: &ClusterState) -> Result<String> {
+        Ok(format!("{}/kubeconfig", state.dir()))
+    }
+
+    fn status(&self, state: &ClusterState) -> Result<Status>;
+
+    /// Status of the cluster, along with its version and size when the
+    /// provider knows them.
+    fn info(&self, state: &ClusterState) -> Result<ClusterInfo> {
+        Ok(ClusterInfo::from(self.status(state)?))
+    }
+
+    /// Names of the clusters this provider knows about.
+    fn list(&self) -> Result<Vec<String>>;
+}
+
+/// The per node options set in `options`, for providers that create all
+/// nodes alike.
+pub fn node_options(options: &CreateOptions) -> Vec<&'static str> {
+    let mut set = vec![];
+    if !options.node_labels.is_empty() {
+        set.push("node labels");
+    }
+    if !options.node_taints.is_empty() {
+        set.push("node taints");
+    }
+    if !options.node_images.is_empty() {
+        set.push("node images");
+    }
+
+    set
+}
+
+/// The options set in `options` that managed clusters, whose nodes are set
+/// up with the metadata, don't support.
+pub fn managed_options(options: &CreateOptions) -> Vec<&'static str> {
+    let mut set = vec![];
+    if options.control_planes.unwrap_or(1) != 1 {
+        set.push("multiple control planes");
+    }
+    set.extend(node_options(options));
+    // the nodes can't reach this machine
+    if !options.port_mappings.is_empty() {
+        set.push("port mappings");
+    }
+    if options.ecr.is_some() {
+        set.push("ECR registries");
+    }
+    if options.local_registry.is_some() {
+        set.push("local registries");
+    }
+
+    set
+}
+
+/// Fails if any of the `unsupported` options were given to `provider`.
+pub fn reject_unsupported(provider: &str, unsupported: &[&str]) -> Result<()> {
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "The {} provider does not support {}",
+        provider,
+        unsupported.join(", ")
+    ))
+}
+
+/// Waits for a local cluster reachable with `kubeconfig` to be ready,
+/// unless told not to.
+pub fn wait_until_ready(state: &ClusterState, kubeconfig: &str) -> Result<()> {
+    if state.options.no_wait {
+        return Ok(());
+    }
+
+    let timeout = state.options.timeout.unwrap_or(LOCAL_TIMEOUT);
+    wait::until(&format!("cluster {}", state.name), timeout, || {
+        ready::cluster(kubeconfig)
+    })
+}
+
+fn registry() -> Vec<Box<dyn Provider>> {
+    vec![
+        Box::new(KindProvider),
+        Box::new(K3d),
+        Box::new(Minikube),
+        Box::new(DigitalOcean),
+        Box::new(Linode),
+    ]
+}
+
+/// Returns the provider registered with `name`, or one of its aliases.
+/// Other names are looked up as `hake-provider-<name>` plugins.
+pub fn get(name: &str) -> Result<Box<dyn Provider>> {
+    let providers = registry();
+    let mut known: Vec<String> = providers.iter().map(|p| String::from(p.name())).collect();
+
+    if let Some(provider) = providers
+        .into_iter()
+        .find(|p| p.name() == name || p.aliases().contains(&name))
+    {
+        return Ok(provider);
+    }
+    if let Some(plugin) = plugin::find(name) {
+        return Ok(Box::new(plugin));
+    }
+
+    known.extend(plugin::discover());
+    Err(anyhow!(
+        "Unknown provider: {}. Known providers are: {}",
+        name,
+        known.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provider::{self, CreateOptions};
+    use crate::testing::Env;
+
+    #[test]
+    fn test_get() {
+        // plugins in $PATH are known providers too, and there are none in
+        // an empty directory
+        let mut env = Env::new();
+        let home = env.scratch_home("provider-get");
+        env.set("PATH", &home);
+
+        assert_eq!(provider::get("kind").unwrap().name(), "kind");
+        assert_eq!(provider::get("k3d").unwrap().name(), "k3d");
+        assert_eq!(provider::get("minikube").unwrap().name(), "minikube");
+        assert_eq!(provider::get("do").unwrap().name(), "digitalocean");
+        assert_eq!(
+            provider::get("digitalocean").unwrap().name(),
+            "digitalocean"
+        );
+        assert_eq!(provider::get("lke").unwrap().name(), "linode");
+
+        let err = provider::get("gke").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Unknown provider: gke. Known providers are: kind, k3d, minikube, digitalocean, linode"
+        );
+    }
+
+    #[test]
+    fn test_override_with() {
+        let mut options = CreateOptions {
+            workers: Some(1),
+            k8s_version: Some(String::from("1.26")),
+            port_mappings: vec![String::from("80:80")],
+            node_labels: vec![String::from("worker:disk=ssd")],
+            ..Default::default()
+        };
+        options.override_with(CreateOptions {
+            workers: Some(3),
+            port_mappings: vec![String::from("8080:80")],
+            no_wait: true,
+            ..Default::default()
+        });
+
+        assert_eq!(options.workers, Some(3));
+        assert_eq!(options.k8s_version, Some(String::from("1.26")));
+        assert_eq!(options.port_mappings, vec!["8080:80"]);
+        assert_eq!(options.node_labels, vec!["worker:disk=ssd"]);
+        assert!(options.no_wait);
+    }
+}
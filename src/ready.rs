@@ -0,0 +1,366 @@
+// Readiness checks run with kubectl against a cluster's kubeconfig, for
+// clusters and for the addons installed into them.
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use std::vec::Vec;
+
+use crate::cmd::{self, CommandError};
+use crate::wait::Progress;
+
+/// Runs `kubectl get ... -o json`.
+fn get(kubeconfig: &str, args: &[&str]) -> Result<Value> {
+    let mut all = vec!["--kubeconfig", kubeconfig, "get"];
+    all.extend(args);
+    all.extend(&["-o", "json"]);
+
+    let output = cmd::run("kubectl", &all).map_err(|e| match e.downcast_ref::<CommandError>() {
+        // only the reason is interesting while waiting
+        Some(err) => anyhow!("{}", err.reason()),
+        None => e,
+    })?;
+
+    Ok(serde_json::from_str(&output)?)
+}
+
+fn name(item: &Value) -> &str {
+    item["metadata"]["name"].as_str().unwrap_or("unknown")
+}
+
+/// Nodes in a `kubectl get nodes` list without a `Ready=True` condition.
+fn nodes_not_ready(nodes: &Value) -> Vec<String> {
+    let empty = vec![];
+    let items = nodes["items"].as_array().unwrap_or(&empty);
+    if items.is_empty() {
+        return vec![String::from("no nodes registered")];
+    }
+
+    items
+        .iter()
+        .filter(|node| {
+            !node["status"]["conditions"]
+                .as_array()
+                .unwrap_or(&empty)
+                .iter()
+                .any(|c| c["type"] == "Ready" && c["status"] == "True")
+        })
+        .map(|node| format!("node {} is NotReady", name(node)))
+        .collect()
+}
+
+/// Pods in a `kubectl get pods` list that are not running with all of
+/// their containers ready. Completed pods are fine.
+fn pods_not_ready(pods: &Value) -> Vec<String> {
+    let empty = vec![];
+
+    pods["items"]
+        .as_array()
+        .unwrap_or(&empty)
+        .iter()
+        .filter_map(|pod| {
+            let phase = pod["status"]["phase"].as_str().unwrap_or("Unknown");
+            let ready = pod["status"]["containerStatuses"]
+                .as_array()
+                .unwrap_or(&empty)
+                .iter()
+                .all(|c| c["ready"] == true);
+
+            match phase {
+                "Succeeded" => None,
+                "Running" if ready => None,
+                "Running" => Some(format!("pod {} is not ready", name(pod))),
+                _ => Some(format!("pod {} is {}", name(pod), phase)),
+            }
+        })
+        .collect()
+}
+
+/// Whether `object` has condition `kind` with status True.
+fn has_condition(object: &Value, kind: &str) -> bool {
+    object["status"]["conditions"]
+        .as_array()
+        .map(|c| c.iter().any(|c| c["type"] == kind && c["status"] == "True"))
+        .unwrap_or(false)
+}
+
+/// Why a deployment is not available with all of its replicas updated,
+/// if it isn't.
+fn deployment_not_ready(deployment: &Value) -> Option<String> {
+    let wanted = deployment["spec"]["replicas"].as_u64().unwrap_or(1);
+    let updated = deployment["status"]["updatedReplicas"]
+        .as_u64()
+        .unwrap_or(0);
+    let available = deployment["status"]["availableReplicas"]
+        .as_u64()
+        .unwrap_or(0);
+
+    if has_condition(deployment, "Available") && updated >= wanted && available >= wanted {
+        None
+    } else {
+        Some(format!(
+            "deployment {} has {}/{} replicas available",
+            name(deployment),
+            available,
+            wanted
+        ))
+    }
+}
+
+/// Services behind the webhooks in a list of webhook configurations, as
+/// namespace and name. Webhooks without a CA bundle are reported, as the
+/// API server can't call them yet.
+fn webhook_services(configs: &Value) -> (Vec<(String, String)>, Vec<String>) {
+    let empty = vec![];
+    let mut services = vec![];
+    let mut pending = vec![];
+
+    for config in configs["items"].as_array().unwrap_or(&empty) {
+        for webhook in config["webhooks"].as_array().unwrap_or(&empty) {
+            let client = &webhook["clientConfig"];
+            if client["caBundle"].as_str().unwrap_or("").is_empty() {
+                pending.push(format!(
+                    "webhook {} has no CA bundle",
+                    webhook["name"].as_str().unwrap_or("unknown")
+                ));
+            }
+
+            let service = &client["service"];
+            if let (Some(namespace), Some(name)) =
+                (service["namespace"].as_str(), service["name"].as_str())
+            {
+                let service = (String::from(namespace), String::from(name));
+                if !services.contains(&service) {
+                    services.push(service);
+                }
+            }
+        }
+    }
+
+    (services, pending)
+}
+
+/// Whether an endpoints object has an address ready to take requests.
+fn endpoints_ready(endpoints: &Value) -> bool {
+    endpoints["subsets"]
+        .as_array()
+        .map(|s| {
+            s.iter().any(|s| {
+                s["addresses"]
+                    .as_array()
+                    .map(|a| !a.is_empty())
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Splits `namespace/name`, objects without namespace are in `default`.
+fn namespaced(object: &str) -> (&str, &str) {
+    match object.find('/') {
+        Some(i) => (&object[..i], &object[i + 1..]),
+        None => ("default", object),
+    }
+}
+
+/// What is missing for deployment `namespace/name` to be available.
+pub fn deployment(kubeconfig: &str, deployment: &str) -> Vec<String> {
+    let (namespace, name) = namespaced(deployment);
+
+    match get(kubeconfig, &["deployment", "--namespace", namespace, name]) {
+        Ok(deployment) => deployment_not_ready(&deployment).into_iter().collect(),
+        Err(e) => vec![format!("deployment {}: {}", name, e)],
+    }
+}
+
+/// What is missing for custom resource definition `name` to be
+/// established, so resources of its kind can be created.
+pub fn crd(kubeconfig: &str, name: &str) -> Vec<String> {
+    match get(kubeconfig, &["customresourcedefinition", name]) {
+        Ok(crd) if has_condition(&crd, "Established") => vec![],
+        Ok(_) => vec![format!("CRD {} is not established", name)],
+        Err(e) => vec![format!("CRD {}: {}", name, e)],
+    }
+}
+
+/// What is missing for the webhooks of the validating and mutating
+/// configurations named `name` to answer: a CA bundle, and a ready
+/// endpoint behind their services.
+pub fn webhook(kubeconfig: &str, name: &str) -> Vec<String> {
+    let selector = format!("metadata.name={}", name);
+    let configs = match get(
+        kubeconfig,
+        &[
+            "validatingwebhookconfigurations,mutatingwebhookconfigurations",
+            "--field-selector",
+            &selector,
+        ],
+    ) {
+        Ok(configs) => configs,
+        Err(e) => return vec![format!("webhook {}: {}", name, e)],
+    };
+    if configs["items"].as_array().map(|i| i.len()).unwrap_or(0) == 0 {
+        return vec![format!("webhook configuration {} not found", name)];
+    }
+
+    let (services, mut pending) = webhook_services(&configs);
+    for (namespace, service) in services {
+        match get(
+            kubeconfig,
+            &["endpoints", "--namespace", &namespace, &service],
+        ) {
+            Ok(endpoints) if endpoints_ready(&endpoints) => {}
+            Ok(_) => pending.push(format!(
+                "webhook service {} has no ready endpoints",
+                service
+            )),
+            Err(e) => pending.push(format!("webhook service {}: {}", service, e)),
+        }
+    }
+
+    pending
+}
+
+/// What keeps the object in `manifest` from being created, tried with a
+/// server side dry run so admission webhooks are called but nothing is
+/// created.
+pub fn probe(kubeconfig: &str, manifest: &str) -> Vec<String> {
+    let args = [
+        "--kubeconfig",
+        kubeconfig,
+        "create",
+        "--dry-run=server",
+        "-f",
+        "-",
+    ];
+
+    match cmd::run_with_input("kubectl", &args, manifest) {
+        Ok(_) => vec![],
+        Err(e) => match e.downcast_ref::<CommandError>() {
+            Some(err) => vec![format!("probe: {}", err.reason())],
+            None => vec![format!("probe: {}", e)],
+        },
+    }
+}
+
+/// The cluster is ready when all of its nodes are Ready and the pods in
+/// kube-system are running.
+pub fn cluster(kubeconfig: &str) -> Result<Progress> {
+    let nodes = match get(kubeconfig, &["nodes"]) {
+        Ok(nodes) => nodes,
+        Err(e) => return Ok(Progress::Pending(format!("API server: {}", e))),
+    };
+    let mut pending = nodes_not_ready(&nodes);
+
+    match get(kubeconfig, &["pods", "--namespace", "kube-system"]) {
+        Ok(pods) => pending.extend(pods_not_ready(&pods)),
+        Err(e) => pending.push(format!("API server: {}", e)),
+    }
+
+    if pending.is_empty() {
+        Ok(Progress::Done)
+    } else {
+        Ok(Progress::Pending(pending.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ready;
+
+    #[test]
+    fn test_nodes_not_ready() {
+        let nodes = serde_json::json!({"items": [
+            {"metadata": {"name": "tests-control-plane"},
+             "status": {"conditions": [{"type": "Ready", "status": "True"}]}},
+            {"metadata": {"name": "tests-worker"},
+             "status": {"conditions": [{"type": "Ready", "status": "False"}]}},
+        ]});
+
+        assert_eq!(
+            ready::nodes_not_ready(&nodes),
+            vec!["node tests-worker is NotReady"]
+        );
+        assert_eq!(
+            ready::nodes_not_ready(&serde_json::json!({"items": []})),
+            vec!["no nodes registered"]
+        );
+    }
+
+    #[test]
+    fn test_pods_not_ready() {
+        let pods = serde_json::json!({"items": [
+            {"metadata": {"name": "etcd"},
+             "status": {"phase": "Running", "containerStatuses": [{"ready": true}]}},
+            {"metadata": {"name": "coredns-1"},
+             "status": {"phase": "Running", "containerStatuses": [{"ready": false}]}},
+            {"metadata": {"name": "coredns-2"}, "status": {"phase": "Pending"}},
+            {"metadata": {"name": "job"}, "status": {"phase": "Succeeded"}},
+        ]});
+
+        assert_eq!(
+            ready::pods_not_ready(&pods),
+            vec!["pod coredns-1 is not ready", "pod coredns-2 is Pending"]
+        );
+    }
+
+    #[test]
+    fn test_deployment_not_ready() {
+        let deployment = |available: u64| {
+            serde_json::json!({
+                "metadata": {"name": "cert-manager-webhook"},
+                "spec": {"replicas": 1},
+                "status": {
+                    "updatedReplicas": 1,
+                    "availableReplicas": available,
+                    "conditions": [{"type": "Available", "status": if available > 0 { "True" } else { "False" }}],
+                },
+            })
+        };
+
+        assert_eq!(ready::deployment_not_ready(&deployment(1)), None);
+        assert_eq!(
+            ready::deployment_not_ready(&deployment(0)),
+            Some(String::from(
+                "deployment cert-manager-webhook has 0/1 replicas available"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_webhook_services() {
+        let configs = serde_json::json!({"items": [
+            {"metadata": {"name": "cert-manager-webhook"},
+             "webhooks": [{"name": "webhook.cert-manager.io", "clientConfig": {
+                 "caBundle": "LS0t",
+                 "service": {"namespace": "cert-manager", "name": "cert-manager-webhook"}}}]},
+            {"metadata": {"name": "cert-manager-webhook"},
+             "webhooks": [{"name": "webhook.cert-manager.io", "clientConfig": {
+                 "service": {"namespace": "cert-manager", "name": "cert-manager-webhook"}}}]},
+        ]});
+
+        assert_eq!(
+            ready::webhook_services(&configs),
+            (
+                vec![(
+                    String::from("cert-manager"),
+                    String::from("cert-manager-webhook")
+                )],
+                vec![String::from(
+                    "webhook webhook.cert-manager.io has no CA bundle"
+                )]
+            )
+        );
+        assert!(ready::endpoints_ready(
+            &serde_json::json!({"subsets": [{"addresses": [{"ip": "10.244.0.5"}]}]})
+        ));
+        assert!(!ready::endpoints_ready(
+            &serde_json::json!({"subsets": [{"notReadyAddresses": [{"ip": "10.244.0.5"}]}]})
+        ));
+        assert_eq!(
+            ready::namespaced("cert-manager/webhook"),
+            ("cert-manager", "webhook")
+        );
+        assert_eq!(ready::namespaced("webhook"), ("default", "webhook"));
+    }
+}
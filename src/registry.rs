@@ -0,0 +1,242 @@
+// A local docker registry managed by hake, reachable from the host as
+// `localhost:5000` and from cluster nodes through their docker network.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+use std::vec::Vec;
+
+use crate::cmd::{self, CommandError};
+
+/// Name of the registry container created by `hake registry create`.
+pub const NAME: &str = "hake-registry";
+const IMAGE: &str = "registry:2";
+/// Volume keeping the images pushed to the registry.
+const VOLUME: &str = "hake-registry-data";
+/// Network kind puts its nodes in.
+pub const KIND_NETWORK: &str = "kind";
+
+/// Address images are pushed to and pulled from, like
+/// `localhost:5000/my-image`.
+pub const HOST: &str = "localhost:5000";
+
+/// Tells tools in the cluster where the local registry is, see
+/// https://github.com/kubernetes/enhancements/tree/master/keps/sig-cluster-lifecycle/generic/1755-communicating-a-local-registry
+const HOSTING_CONFIG_MAP: &str = r#"apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: local-registry-hosting
+  namespace: kube-public
+data:
+  localRegistryHosting.v1: |
+    host: "localhost:5000"
+    help: "https://kind.sigs.k8s.io/docs/user/local-registry/"
+"#;
+
+#[derive(Debug, PartialEq)]
+pub struct RegistryStatus {
+    /// Docker state of the container, like `running` or `exited`. None if
+    /// there is no registry container.
+    pub state: Option<String>,
+    /// Docker networks the container is attached to.
+    pub networks: Vec<String>,
+}
+
+/// Runs `docker inspect` on `object`, returning None if it does not exist.
+fn inspect(kind: &str, object: &str) -> Result<Option<Value>> {
+    match cmd::run("docker", &[kind, "inspect", object]) {
+        Ok(output) => {
+            let value: Value = serde_json::from_str(&output)?;
+            Ok(Some(value[0].clone()))
+        }
+        Err(e) => match e.downcast_ref::<CommandError>() {
+            Some(err) if err.reason().contains("No such") => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+fn registry_status(container: &Value) -> RegistryStatus {
+    let networks = container["NetworkSettings"]["Networks"]
+        .as_object()
+        .map(|n| n.keys().cloned().collect())
+        .unwrap_or_default();
+
+    RegistryStatus {
+        state: container["State"]["Status"].as_str().map(String::from),
+        networks,
+    }
+}
+
+/// Status of the registry container `name`.
+pub fn status_of(name: &str) -> Result<RegistryStatus> {
+    Ok(match inspect("container", name)? {
+        Some(container) => registry_status(&container),
+        None => RegistryStatus {
+            state: None,
+            networks: vec![],
+        },
+    })
+}
+
+/// Status of the registry managed by hake.
+pub fn status() -> Result<RegistryStatus> {
+    status_of(NAME)
+}
+
+/// Attaches container `name` to `network` so cluster nodes can reach it
+/// by name. Does nothing until a cluster creates the network.
+fn connect(name: &str, network: &str, status: &RegistryStatus) -> Result<()> {
+    if status.networks.iter().any(|n| n == network) || inspect("network", network)?.is_none() {
+        return Ok(());
+    }
+
+    log::info!("Connecting {} to the {} network", name, network);
+    cmd::run("docker", &["network", "connect", network, name])?;
+
+    Ok(())
+}
+
+/// Starts the registry, creating it and its volume if needed.
+pub fn create() -> Result<()> {
+    let status = status()?;
+    match &status.state {
+        Some(state) if state == "running" => log::info!("Registry {} is already running", NAME),
+        Some(_) => {
+            log::info!("Starting registry {}", NAME);
+            cmd::run("docker", &["start", NAME])?;
+        }
+        None => {
+            log::info!("Creating registry {}", NAME);
+            cmd::run(
+                "docker",
+                &[
+                    "run",
+                    "--detach",
+                    "--restart=always",
+                    "--name",
+                    NAME,
+                    "--publish",
+                    "127.0.0.1:5000:5000",
+                    "--volume",
+                    &format!("{}:/var/lib/registry", VOLUME),
+                    IMAGE,
+                ],
+            )?;
+        }
+    }
+
+    connect(NAME, KIND_NETWORK, &status)
+}
+
+/// Removes the registry container, and the images in it if
+/// `remove_volume` is set.
+pub fn delete(remove_volume: bool) -> Result<()> {
+    if status()?.state.is_some() {
+        log::info!("Removing registry {}", NAME);
+        cmd::run("docker", &["rm", "--force", NAME])?;
+    }
+    if remove_volume && inspect("volume", VOLUME)?.is_some() {
+        log::info!("Removing volume {}", VOLUME);
+        cmd::run("docker", &["volume", "rm", VOLUME])?;
+    }
+
+    Ok(())
+}
+
+/// Checks that registry container `name` exists before a cluster is
+/// configured to use it.
+pub(crate) fn check(name: &str) -> Result<()> {
+    if status_of(name)?.state.is_none() {
+        return Err(anyhow!(
+            "Registry container {} does not exist. Create one with: hake registry create",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Makes registry container `name` reachable by name from the nodes of a
+/// cluster that was just created on `network`.
+pub(crate) fn join(name: &str, network: &str) -> Result<()> {
+    connect(name, network, &status_of(name)?)
+}
+
+/// Makes registry container `name` usable as `localhost:5000` from a
+/// cluster that was just created on `network`: the container joins the
+/// network, and the cluster gets the `local-registry-hosting` ConfigMap.
+pub(crate) fn attach(name: &str, network: &str, kubeconfig: &str) -> Result<()> {
+    join(name, network)?;
+
+    cmd::run_with_input(
+        "kubectl",
+        &["--kubeconfig", kubeconfig, "apply", "-f", "-"],
+        HOSTING_CONFIG_MAP,
+    )
+    .context("Could not publish the local registry in the cluster")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd;
+    use crate::registry::{self, RegistryStatus};
+    use crate::testing::FakeRunner;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_registry_status() {
+        let container = serde_json::json!({
+            "State": {"Status": "running"},
+            "NetworkSettings": {"Networks": {"bridge": {}, "kind": {}}},
+        });
+
+        assert_eq!(
+            registry::registry_status(&container),
+            RegistryStatus {
+                state: Some(String::from("running")),
+                networks: vec![String::from("bridge"), String::from("kind")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let runner = Rc::new(
+            FakeRunner::new()
+                .fail(
+                    "container inspect",
+                    "Error: No such container: hake-registry",
+                )
+                .on("network inspect kind", r#"[{"Name": "kind"}]"#),
+        );
+
+        cmd::with_runner(runner.clone(), registry::create).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "docker container inspect hake-registry",
+                "docker run --detach --restart=always --name hake-registry \
+                 --publish 127.0.0.1:5000:5000 --volume hake-registry-data:/var/lib/registry registry:2",
+                "docker network inspect kind",
+                "docker network connect kind hake-registry",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let runner = Rc::new(FakeRunner::new().fail("inspect", "Error: No such container: reg"));
+
+        assert_eq!(
+            cmd::with_runner(runner, || registry::check("reg"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Registry container reg does not exist. Create one with: hake registry create"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+// Building blocks of the managed-cloud providers: a JSON API client with
+// bearer auth, pagination and errors carrying the API message, plus the
+// `key=value&...` metadata they are configured with.
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{self, ACCEPT, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::wait::{self, Progress};
+
+#[derive(Debug)]
+pub struct ApiError {
+    /// The request, like `GET https://api.linode.com/v4/lke/clusters`.
+    pub request: String,
+    pub status: StatusCode,
+    /// The message in the error response, or its body.
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` failed with status {}", self.request, self.status)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Keeps waiting through errors of a server that is failing or throttling
+/// requests, which happens while clusters are provisioned. Other errors
+/// stop the wait.
+pub fn retry_transient(progress: Result<Progress>) -> Result<Progress> {
+    match progress {
+        Err(e) => match e.downcast_ref::<ApiError>() {
+            Some(err)
+                if err.status.is_server_error() || err.status == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                Ok(Progress::Pending(err.to_string()))
+            }
+            _ => Err(e),
+        },
+        progress => progress,
+    }
+}
+
+/// The useful part of an error response. APIs put it in `message`,
+/// `reason` or a list of `errors`.
+fn error_message(body: &str) -> String {
+    let value: Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return String::from(body.trim()),
+    };
+
+    let message = value["message"]
+        .as_str()
+        .or_else(|| value["reason"].as_str())
+        .or_else(|| value["errors"][0]["reason"].as_str())
+        .or_else(|| value["errors"][0]["message"].as_str());
+
+    match message {
+        Some(message) => String::from(message),
+        None => String::from(body.trim()),
+    }
+}
+
+/// Link to the next page of a list response. Both `links.pages.next` URLs
+/// and `page`/`pages` counters are understood.
+fn next_page(path: &str, body: &Value) -> Option<String> {
+    if let Some(next) = body["links"]["pages"]["next"].as_str() {
+        return Some(String::from(next));
+    }
+
+    match (body["page"].as_u64(), body["pages"].as_u64()) {
+        (Some(page), Some(pages)) if page < pages => {
+            let path = path.split('?').next().unwrap_or(path);
+            Some(format!("{}?page={}", path, page + 1))
+        }
+        _ => None,
+    }
+}
+
+/// Client of the REST API of a provider.
+pub struct Api {
+    provider: &'static str,
+    base: String,
+    client: Client,
+}
+
+impl Api {
+    /// Authenticates with the token in the `token_env` variable. The base
+    /// URL can be changed with `url_env`, for proxies and tests.
+    pub fn new(
+        provider: &'static str,
+        token_env: &str,
+        url_env: &str,
+        default_url: &str,
+    ) -> Result<Api> {
+        #[cfg(test)]
+        crate::testing::assert_env_locked(token_env);
+
+        let token = env::var(token_env).with_context(|| {
+            format!(
+                "{} needs to be set to use the {} provider",
+                token_env, provider
+            )
+        })?;
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+        headers.insert(ACCEPT, header::HeaderValue::from_static("application/json"));
+
+        Ok(Api {
+            provider,
+            base: env::var(url_env).unwrap_or_else(|_| String::from(default_url)),
+            client: Client::builder().default_headers(headers).build()?,
+        })
+    }
+
+    /// Full URL of `path`. Absolute URLs, like the pages of a list, are
+    /// kept as they are.
+    pub fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            String::from(path)
+        } else {
+            format!("{}{}", self.base.trim_end_matches('/'), path)
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client.request(method, &self.url(path))
+    }
+
+    fn send(&self, method: Method, path: &str, request: RequestBuilder) -> Result<Response> {
+        let name = format!("{} {}", method, self.url(path));
+        let resp = request
+            .send()
+            .with_context(|| format!("Could not reach the {} API", self.provider))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            return Err(ApiError {
+                request: name,
+                status,
+                message: error_message(&resp.text().unwrap_or_default()),
+            }
+            .into());
+        }
+
+        Ok(resp)
+    }
+
+    /// Gets the resource at `path`, or None if it does not exist.
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        match self.send(Method::GET, path, self.request(Method::GET, path)) {
+            Ok(resp) => Ok(Some(resp.json()?)),
+            Err(e) => match e.downcast_ref::<ApiError>() {
+                Some(err) if err.status == StatusCode::NOT_FOUND => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Gets every item of a list, following its pages. The items of each
+    /// page are in the `key` field.
+    pub fn list<T: DeserializeOwned>(&self, path: &str, key: &str) -> Result<Vec<T>> {
+        let mut items = vec![];
+        let mut next = Some(String::from(path));
+
+        while let Some(path) = next {
+            let body: Value = self
+                .send(Method::GET, &path, self.request(Method::GET, &path))?
+                .json()?;
+            if let Some(page) = body.get(key) {
+                items.extend(serde_json::from_value::<Vec<T>>(page.clone())?);
+            }
+            next = next_page(&path, &body);
+        }
+
+        Ok(items)
+    }
+
+    pub fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        let request = self
+            .request(Method::POST, path)
+            .header(CONTENT_TYPE, "application/json")
+            .json(body);
+
+        Ok(self.send(Method::POST, path, request)?.json()?)
+    }
+
+    pub fn delete(&self, path: &str) -> Result<()> {
+        self.send(Method::DELETE, path, self.request(Method::DELETE, path))?;
+
+        Ok(())
+    }
+
+    /// Polls `path` until the kubeconfig can be downloaded, and writes it
+    /// to `file`. APIs that wrap the kubeconfig get it out with `decode`.
+    pub fn download_kubeconfig<F>(
+        &self,
+        path: &str,
+        file: &str,
+        timeout: Duration,
+        decode: F,
+    ) -> Result<()>
+    where
+        F: Fn(String) -> Result<String>,
+    {
+        wait::until("the kubeconfig", timeout, || {
+            let resp = match self.send(Method::GET, path, self.request(Method::GET, path)) {
+                Ok(resp) => resp,
+                Err(e) => match e.downcast_ref::<ApiError>() {
+                    // not available while the cluster is starting
+                    Some(err) => {
+                        return Ok(Progress::Pending(format!("status code is {}", err.status)))
+                    }
+                    None => return Err(e),
+                },
+            };
+
+            let kubeconfig = decode(resp.text()?)?;
+            File::create(file)?.write_all(kubeconfig.as_bytes())?;
+
+            Ok(Progress::Done)
+        })
+    }
+}
+
+/// Splits metadata like `region=lon1&nodepool.count=2`. Fields without a
+/// value are ignored.
+pub fn parse_metadata(metadata: &str) -> HashMap<String, String> {
+    let fields: Vec<&str> = metadata.split('&').collect();
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    for field in fields {
+        let split_field: Vec<&str> = field.splitn(2, '=').collect();
+        if split_field.len() != 2 {
+            continue;
+        }
+
+        map.insert(split_field[0].to_string(), split_field[1].to_string());
+    }
+
+    map
+}
+
+/// Errors for the fields in `metadata` that are not `key=value`.
+pub fn invalid_fields(metadata: &str) -> Vec<String> {
+    metadata
+        .split('&')
+        .filter(|f| !f.is_empty() && !f.contains('='))
+        .map(|f| format!("{}: expected key=value", f))
+        .collect()
+}
+
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!(
+            "{}: expected true or false, got \"{}\"",
+            key, value
+        )),
+    }
+}
+
+pub fn parse_count(key: &str, value: &str) -> Result<u16, String> {
+    value
+        .parse::<u16>()
+        .map_err(|_| format!("{}: expected a number, got \"{}\"", key, value))
+}
+
+pub fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Turns the errors collected while parsing the metadata of `provider`
+/// into one error listing all of them.
+pub fn metadata_errors(provider: &str, errors: Vec<String>) -> Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "Invalid {} metadata:\n  - {}",
+        provider,
+        errors.join("\n  - ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rest::{self, Api, ApiError};
+    use crate::testing::{Env, MockServer};
+    use crate::wait::Progress;
+    use anyhow::Context;
+    use reqwest::StatusCode;
+    use std::collections::HashMap;
+
+    // Taken from https://stackoverflow.com/a/27582993/75928
+    macro_rules! map(
+    { $($key:expr => $value:expr),+ } => {
+        {
+            let mut m = ::std::collections::HashMap::new();
+            $(
+                m.insert($key, $value);
+            )+
+            m
+        }
+    };
+    );
+
+    #[test]
+    fn test_parse_metadata() {
+        assert_eq!(
+            rest::parse_metadata("region=lon1"),
+            map! { "region".to_string() => "lon1".to_string() }
+        );
+
+        assert_eq!(
+            rest::parse_metadata("region=lon1&attr1=value1"),
+            map! { "region".to_string() => "lon1".to_string(), "attr1".to_string() => "value1".to_string() }
+        );
+
+        assert_eq!(rest::parse_metadata("region"), HashMap::new());
+        assert_eq!(rest::parse_metadata("&"), HashMap::new());
+        assert_eq!(rest::parse_metadata(""), HashMap::new());
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(
+            rest::error_message(r#"{"id": "not_found", "message": "The resource was not found."}"#),
+            "The resource was not found."
+        );
+        assert_eq!(
+            rest::error_message(r#"{"errors": [{"reason": "Invalid region"}]}"#),
+            "Invalid region"
+        );
+        assert_eq!(rest::error_message("Bad Gateway\n"), "Bad Gateway");
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let failed = |status: StatusCode| -> anyhow::Result<Progress> {
+            Err(ApiError {
+                request: String::from("GET /clusters/abc"),
+                status,
+                message: String::new(),
+            })
+            .context("Could not get Cluster with id: abc")
+        };
+
+        for status in &[
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            match rest::retry_transient(failed(*status)).unwrap() {
+                Progress::Pending(message) => assert!(message.starts_with("`GET /clusters/abc`")),
+                Progress::Done => panic!("{} is not done", status),
+            }
+        }
+        assert!(rest::retry_transient(failed(StatusCode::FORBIDDEN)).is_err());
+        assert!(rest::retry_transient(Err(anyhow::anyhow!("invalid JSON"))).is_err());
+    }
+
+    #[test]
+    fn test_api() {
+        let server = MockServer::start(|method, path, _| match (method, path) {
+            ("GET", "/v1/things") => (
+                200,
+                String::from(r#"{"things": [1, 2], "page": 1, "pages": 2}"#),
+            ),
+            ("GET", "/v1/things?page=2") => (
+                200,
+                String::from(r#"{"things": [3], "page": 2, "pages": 2}"#),
+            ),
+            ("POST", "/v1/things") => (422, String::from(r#"{"message": "no more things"}"#)),
+            _ => (404, String::new()),
+        });
+
+        let mut env = Env::new();
+        env.remove("HAKE_TEST_API_KEY");
+        assert_eq!(
+            Api::new("test", "HAKE_TEST_API_KEY", "HAKE_TEST_API_URL", "")
+                .err()
+                .unwrap()
+                .to_string(),
+            "HAKE_TEST_API_KEY needs to be set to use the test provider"
+        );
+
+        env.set("HAKE_TEST_API_KEY", "token");
+        env.set("HAKE_TEST_API_URL", &format!("{}/v1", server.url));
+        let api = Api::new("test", "HAKE_TEST_API_KEY", "HAKE_TEST_API_URL", "").unwrap();
+
+        assert_eq!(api.list::<u32>("/things", "things").unwrap(), vec![1, 2, 3]);
+        assert_eq!(api.get::<u32>("/missing").unwrap(), None);
+        assert_eq!(
+            api.post::<_, u32>("/things", &4).err().unwrap().to_string(),
+            format!(
+                "`POST {}/v1/things` failed with status 422 Unprocessable Entity: no more things",
+                server.url
+            )
+        );
+    }
+}
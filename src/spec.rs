@@ -0,0 +1,345 @@
+#![allow(non_snake_case)]
+
+// Declarative cluster specs, to be used with `hake create -f hake.yaml`.
+// Files ending in `.toml` are read as TOML, anything else as YAML.
+
+use anyhow::{anyhow, Result};
+use serde::de::{self, Deserializer, Visitor};
+use serde_derive::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::vec::Vec;
+
+use regex::Regex;
+
+use crate::addons;
+use crate::ports;
+use crate::provider::{self, CreateOptions};
+
+/// Versions of the spec format this version of hake understands.
+const API_VERSIONS: &[&str] = &["hake/v1alpha1"];
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Registries {
+    pub ecr: Option<String>,
+    /// Name of the local registry container.
+    pub local: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Nodes {
+    pub controlPlanes: Option<u32>,
+    pub workers: Option<u32>,
+    /// Labels like `worker:disk=ssd`.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Taints like `worker-1:dedicated=db:NoSchedule`.
+    #[serde(default)]
+    pub taints: Vec<String>,
+    /// Images like `worker-1:kindest/node:v1.26.6` or `worker-1:1.26`.
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+/// A metadata value. Numbers and booleans are taken as written, so values
+/// like `nodepool.count: 3` don't need quoting.
+#[derive(Debug, PartialEq)]
+pub struct MetadataValue(pub String);
+
+impl<'de> serde::Deserialize<'de> for MetadataValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ScalarVisitor;
+
+        impl<'de> Visitor<'de> for ScalarVisitor {
+            type Value = MetadataValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string, number or boolean")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<MetadataValue, E> {
+                Ok(MetadataValue(String::from(v)))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<MetadataValue, E> {
+                Ok(MetadataValue(v.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<MetadataValue, E> {
+                Ok(MetadataValue(v.to_string()))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<MetadataValue, E> {
+                Ok(MetadataValue(v.to_string()))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<MetadataValue, E> {
+                Ok(MetadataValue(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(ScalarVisitor)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterSpec {
+    pub apiVersion: String,
+    pub kind: String,
+    pub name: Option<String>,
+    pub provider: Option<String>,
+    pub kubernetesVersion: Option<String>,
+    #[serde(default)]
+    pub nodes: Nodes,
+    #[serde(default)]
+    pub registries: Registries,
+    #[serde(default)]
+    pub portMappings: Vec<String>,
+    #[serde(default)]
+    pub addons: Vec<String>,
+    /// Provider specific settings, same keys as `--metadata`.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, MetadataValue>,
+}
+
+impl ClusterSpec {
+    pub fn from_file(path: &str) -> Result<ClusterSpec> {
+        let mut contents = String::new();
+        File::open(path)
+            .map_err(|e| anyhow!("Could not open cluster spec {}: {}", path, e))?
+            .read_to_string(&mut contents)?;
+
+        let spec = if path.ends_with(".toml") {
+            ClusterSpec::from_toml(&contents)
+        } else {
+            ClusterSpec::from_str(&contents)
+        };
+
+        spec.map_err(|e| anyhow!("Invalid cluster spec {}: {}", path, e))
+    }
+
+    fn from_str(contents: &str) -> Result<ClusterSpec> {
+        ClusterSpec::checked(serde_yaml::from_str(contents)?)
+    }
+
+    fn from_toml(contents: &str) -> Result<ClusterSpec> {
+        ClusterSpec::checked(toml::from_str(contents)?)
+    }
+
+    /// Fails with every problem found in `spec`.
+    fn checked(spec: ClusterSpec) -> Result<ClusterSpec> {
+        let errors = spec.validate();
+        if !errors.is_empty() {
+            return Err(anyhow!("\n  - {}", errors.join("\n  - ")));
+        }
+
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if !API_VERSIONS.contains(&&self.apiVersion[..]) {
+            errors.push(format!(
+                "apiVersion: unsupported version \"{}\", expected one of: {}",
+                self.apiVersion,
+                API_VERSIONS.join(", ")
+            ));
+        }
+        if self.kind != "Cluster" {
+            errors.push(format!("kind: expected \"Cluster\", got \"{}\"", self.kind));
+        }
+        if let Some(name) = &self.name {
+            let re = Regex::new(r"^[a-z0-9]([-a-z0-9]*[a-z0-9])?$").unwrap();
+            if !re.is_match(name) {
+                errors.push(format!(
+                    "name: \"{}\" must consist of lower case alphanumeric characters or '-'",
+                    name
+                ));
+            }
+        }
+        if let Some(name) = &self.provider {
+            if let Err(e) = provider::get(name) {
+                errors.push(format!("provider: {}", e));
+            }
+        }
+        if self.nodes.controlPlanes == Some(0) {
+            errors.push(String::from(
+                "nodes.controlPlanes: a cluster needs at least one control-plane node",
+            ));
+        }
+        if let Err(e) = ports::parse_all(&self.portMappings) {
+            errors.push(format!("portMappings: {}", e));
+        }
+        for addon in &self.addons {
+            if addons::find(addon).is_err() {
+                errors.push(format!(
+                    "addons: unknown addon \"{}\", expected one of: {}",
+                    addon,
+                    addons::names().join(", ")
+                ));
+            }
+        }
+
+        errors
+    }
+
+    /// Creation options described by this spec.
+    pub fn options(&self) -> CreateOptions {
+        let metadata: Vec<String> = self
+            .metadata
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.0))
+            .collect();
+
+        CreateOptions {
+            ecr: self.registries.ecr.clone(),
+            local_registry: self.registries.local.clone(),
+            port_mappings: self.portMappings.clone(),
+            control_planes: self.nodes.controlPlanes,
+            workers: self.nodes.workers,
+            node_labels: self.nodes.labels.clone(),
+            node_taints: self.nodes.taints.clone(),
+            k8s_version: self.kubernetesVersion.clone(),
+            node_images: self.nodes.images.clone(),
+            metadata: if metadata.is_empty() {
+                None
+            } else {
+                Some(metadata.join("&"))
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spec::ClusterSpec;
+    use crate::testing::Env;
+
+    #[test]
+    fn test_from_str() {
+        let _env = Env::new();
+        let spec = ClusterSpec::from_str(
+            r#"
+apiVersion: hake/v1alpha1
+kind: Cluster
+name: operator-tests
+provider: do
+nodes:
+  workers: 2
+  labels: ["worker:disk=ssd"]
+registries:
+  ecr: xxx.ecr.eu-west-1.amazonaws.com
+portMappings:
+  - "80:80"
+  - 127.0.0.1:5353:53/udp
+addons:
+  - cert-manager
+metadata:
+  region: ams3
+  ha: true
+  nodepool.count: 3
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(spec.name, Some(String::from("operator-tests")));
+        assert_eq!(spec.addons, vec!["cert-manager"]);
+
+        let options = spec.options();
+        assert_eq!(
+            options.ecr,
+            Some(String::from("xxx.ecr.eu-west-1.amazonaws.com"))
+        );
+        assert_eq!(options.local_registry, None);
+        assert_eq!(options.control_planes, None);
+        assert_eq!(options.workers, Some(2));
+        assert_eq!(options.node_labels, vec!["worker:disk=ssd"]);
+        assert_eq!(
+            options.port_mappings,
+            vec!["80:80", "127.0.0.1:5353:53/udp"]
+        );
+        assert_eq!(
+            options.metadata,
+            Some(String::from("ha=true&nodepool.count=3&region=ams3"))
+        );
+    }
+
+    #[test]
+    fn test_from_str_errors() {
+        let _env = Env::new();
+        let err = ClusterSpec::from_str(
+            r#"
+apiVersion: hake/v2
+kind: Cluster
+name: Not_Valid
+provider: gke
+portMappings: ["80:80/http"]
+addons: [cert-manager, istio]
+"#,
+        )
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            err.to_string(),
+            "\n  - apiVersion: unsupported version \"hake/v2\", expected one of: hake/v1alpha1\
+             \n  - name: \"Not_Valid\" must consist of lower case alphanumeric characters or '-'\
+             \n  - provider: Unknown provider: gke. Known providers are: kind, k3d, minikube, digitalocean, linode\
+             \n  - portMappings: Invalid protocol HTTP in port mapping 80:80/http, expected TCP, UDP or SCTP\
+             \n  - addons: unknown addon \"istio\", expected one of: cert-manager, ingress-nginx"
+        );
+
+        let err = ClusterSpec::from_str("apiVersion: hake/v1alpha1\nkind: Cluster\nworkers: 2")
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("unknown field `workers`"));
+
+        let err = ClusterSpec::from_str(
+            "apiVersion: hake/v1alpha1\nkind: Cluster\nmetadata:\n  nodepool: {count: 3}",
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("invalid type: map, expected a string, number or boolean"));
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let _env = Env::new();
+        let spec = ClusterSpec::from_toml(
+            r#"
+apiVersion = "hake/v1alpha1"
+kind = "Cluster"
+name = "operator-tests"
+portMappings = ["80:80"]
+addons = ["cert-manager"]
+
+[nodes]
+workers = 2
+
+[metadata]
+region = "ams3"
+"nodepool.count" = 3
+"#,
+        )
+        .unwrap();
+
+        let options = spec.options();
+        assert_eq!(options.workers, Some(2));
+        assert_eq!(options.port_mappings, vec!["80:80"]);
+        assert_eq!(
+            options.metadata,
+            Some(String::from("nodepool.count=3&region=ams3"))
+        );
+        assert!(ClusterSpec::from_toml("apiVersion = \"hake/v2\"\nkind = \"Cluster\"").is_err());
+    }
+}
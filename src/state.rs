@@ -0,0 +1,283 @@
+// Persistent state of the clusters created by hake. Every cluster has a
+// `state.json` manifest in its directory under ~/.hake.
+
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::addons;
+use crate::provider::CreateOptions;
+
+/// Version of the manifest format, bumped on incompatible changes.
+pub const STATE_VERSION: u32 = 1;
+
+const STATE_FILE: &str = "state.json";
+
+/// An addon installed with `hake add`. The manifests that were applied are
+/// kept in the `addons/<name>` directory of the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstalledAddon {
+    pub name: String,
+    pub version: String,
+    /// Seconds since the Unix epoch.
+    pub installed_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterState {
+    pub version: u32,
+    pub name: String,
+    pub provider: String,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub options: CreateOptions,
+    /// Id of the cluster on the provider side, if it has one.
+    pub remote_id: Option<String>,
+    pub hake_version: String,
+    /// Addons installed into the cluster, in the order they were installed.
+    #[serde(default)]
+    pub addons: Vec<InstalledAddon>,
+    #[serde(skip)]
+    dir: String,
+}
+
+/// Directories of ~/.hake that are not clusters.
+const RESERVED_NAMES: &[&str] = &[addons::CACHE_DIR];
+
+/// Directory of cluster `name`. Fails for names hake uses for itself.
+pub fn cluster_dir(name: &str) -> Result<String> {
+    if RESERVED_NAMES.contains(&name) {
+        return Err(anyhow!(
+            "{} is reserved by hake and can't be used as a cluster name",
+            name
+        ));
+    }
+
+    Ok(format!("{}/{}", crate::get_config_dir()?, name))
+}
+
+pub fn exists(name: &str) -> bool {
+    cluster_dir(name)
+        .map(|dir| Path::new(&dir).exists())
+        .unwrap_or(false)
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl ClusterState {
+    pub fn new(name: &str, provider: &str, options: CreateOptions) -> Result<ClusterState> {
+        Ok(ClusterState {
+            version: STATE_VERSION,
+            name: String::from(name),
+            provider: String::from(provider),
+            created_at: now(),
+            options,
+            remote_id: None,
+            hake_version: String::from(env!("CARGO_PKG_VERSION")),
+            addons: vec![],
+            dir: cluster_dir(name)?,
+        })
+    }
+
+    /// Directory holding this cluster's files.
+    pub fn dir(&self) -> String {
+        self.dir.clone()
+    }
+
+    /// Directory keeping the manifests applied for addon `name`.
+    pub fn addon_dir(&self, name: &str) -> String {
+        format!("{}/addons/{}", self.dir(), name)
+    }
+
+    /// Loads the state of cluster `name`, migrating directories created by
+    /// older versions of hake.
+    pub fn load(name: &str) -> Result<ClusterState> {
+        let dir = cluster_dir(name)?;
+        if !Path::new(&dir).exists() {
+            return Err(anyhow!("Cluster {} does not exist", name));
+        }
+
+        let state_file = format!("{}/{}", dir, STATE_FILE);
+        if !Path::new(&state_file).exists() {
+            let state = ClusterState::migrate(name)?;
+            state.save()?;
+
+            return Ok(state);
+        }
+
+        let mut contents = String::new();
+        File::open(state_file)?.read_to_string(&mut contents)?;
+        let mut state: ClusterState = serde_json::from_str(&contents)?;
+        state.dir = dir;
+
+        if state.version > STATE_VERSION {
+            return Err(anyhow!(
+                "Cluster {} was created by a newer version of hake ({})",
+                name,
+                state.hake_version
+            ));
+        }
+
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let state_file = format!("{}/{}", self.dir(), STATE_FILE);
+        let mut file = File::create(state_file)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Builds the state of a cluster from the loose files older versions of
+    /// hake left in its directory. The name of a local registry container is
+    /// not recorded anywhere, so it can't be recovered.
+    fn migrate(name: &str) -> Result<ClusterState> {
+        let dir = cluster_dir(name)?;
+        let read = |file: &str| -> Option<String> {
+            let mut contents = String::new();
+            File::open(format!("{}/{}", dir, file))
+                .ok()?
+                .read_to_string(&mut contents)
+                .ok()?;
+            Some(contents)
+        };
+
+        let remote_id = read("cluster_uuid").map(|id| id.trim().to_string());
+        let provider = match (read("provider"), &remote_id) {
+            (Some(provider), _) => provider.trim().to_string(),
+            (None, Some(_)) => String::from("digitalocean"),
+            (None, None) => String::from("kind"),
+        };
+
+        let mut options = CreateOptions::default();
+        if let Some(docker_config) = read("docker_config") {
+            options.ecr = migrate_ecr(&docker_config);
+        }
+        if let Some(kind_config) = read("kind_config") {
+            options.port_mappings = migrate_port_mappings(&kind_config);
+        }
+
+        let mut state = ClusterState::new(name, &provider, options)?;
+        state.remote_id = remote_id;
+        state.hake_version = String::from("unknown");
+        if let Ok(created) = fs::metadata(&dir).and_then(|m| m.modified()) {
+            if let Ok(created) = created.duration_since(UNIX_EPOCH) {
+                state.created_at = created.as_secs();
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Removes the cluster directory and everything in it.
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_dir_all(self.dir())?;
+
+        Ok(())
+    }
+}
+
+/// The ECR registry is the only entry in the `auths` of the docker config.
+fn migrate_ecr(docker_config: &str) -> Option<String> {
+    let config: serde_json::Value = serde_json::from_str(docker_config).ok()?;
+    config["auths"].as_object()?.keys().next().cloned()
+}
+
+/// Recovers the port mappings from the first node in the kind config.
+fn migrate_port_mappings(kind_config: &str) -> Vec<String> {
+    let config: serde_yaml::Value = match serde_yaml::from_str(kind_config) {
+        Ok(config) => config,
+        Err(_) => return vec![],
+    };
+    let mappings = match config["nodes"][0]["extraPortMappings"].as_sequence() {
+        Some(mappings) => mappings,
+        None => return vec![],
+    };
+
+    mappings
+        .iter()
+        .filter_map(|mapping| {
+            Some(format!(
+                "{}:{}/{}",
+                mapping["hostPort"].as_u64()?,
+                mapping["containerPort"].as_u64()?,
+                mapping["protocol"].as_str()?
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provider::CreateOptions;
+    use crate::state::{self, ClusterState};
+    use crate::testing::Env;
+
+    #[test]
+    fn test_reserved_names() {
+        let mut env = Env::new();
+        env.scratch_home("state-reserved");
+        let reserved = "cache is reserved by hake and can't be used as a cluster name";
+
+        assert_eq!(
+            ClusterState::new("cache", "kind", CreateOptions::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            reserved
+        );
+        assert_eq!(
+            ClusterState::load("cache").err().unwrap().to_string(),
+            reserved
+        );
+        assert_eq!(
+            crate::create("cache", "kind", CreateOptions::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            reserved
+        );
+    }
+
+    #[test]
+    fn test_migrate_ecr() {
+        assert_eq!(
+            state::migrate_ecr(r#"{"auths":{"xxx.ecr.eu-west-1.amazonaws.com":{"auth":"abc"}}}"#),
+            Some(String::from("xxx.ecr.eu-west-1.amazonaws.com"))
+        );
+        assert_eq!(state::migrate_ecr("{}"), None);
+        assert_eq!(state::migrate_ecr("not json"), None);
+    }
+
+    #[test]
+    fn test_migrate_port_mappings() {
+        let kind_config = r#"---
+kind: Cluster
+apiVersion: kind.x-k8s.io/v1alpha4
+nodes:
+  - role: control-plane
+    extraMounts: []
+    extraPortMappings:
+      - containerPort: 80
+        hostPort: 8080
+        protocol: TCP
+    kubeadmConfigPatches: []
+containerdConfigPatches: []"#;
+
+        assert_eq!(
+            state::migrate_port_mappings(kind_config),
+            vec!["8080:80/TCP"]
+        );
+        assert!(state::migrate_port_mappings("kind: Cluster\nnodes: []").is_empty());
+    }
+}
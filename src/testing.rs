@@ -0,0 +1,238 @@
+// Test helpers: a fake command runner, a mock HTTP server and a way of
+// changing environment variables without tests stepping on each other.
+
+use anyhow::Result;
+
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::cmd::{self, CommandError, CommandRunner};
+use crate::provider::CreateOptions;
+use crate::state::{self, ClusterState};
+
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    static HOLDS_ENV: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Fails tests reading `var` without holding an `Env`, as other tests may
+/// be changing it.
+pub fn assert_env_locked(var: &str) {
+    if !HOLDS_ENV.with(|h| h.get()) {
+        panic!("{} was read by a test that does not hold testing::Env", var);
+    }
+}
+
+/// Changes environment variables while holding a lock, so only one test at
+/// a time sees them. The old values are restored, and the scratch
+/// directories removed, when dropped.
+pub struct Env {
+    saved: Vec<(String, Option<String>)>,
+    scratch: Vec<String>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        let guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        HOLDS_ENV.with(|h| h.set(true));
+
+        Env {
+            saved: vec![],
+            scratch: vec![],
+            _guard: guard,
+        }
+    }
+
+    fn save(&mut self, key: &str) {
+        self.saved.push((String::from(key), env::var(key).ok()));
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.save(key);
+        env::set_var(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.save(key);
+        env::remove_var(key);
+    }
+
+    /// Points HAKE_HOME to an empty scratch directory, and returns it.
+    pub fn scratch_home(&mut self, name: &str) -> String {
+        let dir = env::temp_dir().join(format!("hake-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let dir = dir.to_str().unwrap().to_string();
+        self.set("HAKE_HOME", &dir);
+        self.scratch.push(dir.clone());
+
+        dir
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        for (key, value) in self.saved.iter().rev() {
+            match value {
+                Some(value) => env::set_var(key, value),
+                None => env::remove_var(key),
+            }
+        }
+        for dir in &self.scratch {
+            let _ = fs::remove_dir_all(dir);
+        }
+        HOLDS_ENV.with(|h| h.set(false));
+    }
+}
+
+/// Creates `cluster` with `provider`, hands its state to `check`, then
+/// deletes it and checks nothing is left behind.
+pub fn cluster_flow<F>(cluster: &str, provider: &str, options: CreateOptions, check: F)
+where
+    F: FnOnce(&ClusterState),
+{
+    crate::create(cluster, provider, options).unwrap();
+    check(&ClusterState::load(cluster).unwrap());
+    crate::delete(cluster).unwrap();
+    assert!(!state::exists(cluster));
+}
+
+/// Answers commands containing a pattern with a canned stdout, or fails
+/// them with a canned stderr. Other commands succeed with no output.
+#[derive(Default)]
+pub struct FakeRunner {
+    responses: Vec<(String, Result<String, String>)>,
+    calls: RefCell<Vec<String>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> FakeRunner {
+        FakeRunner::default()
+    }
+
+    pub fn on(mut self, pattern: &str, stdout: &str) -> FakeRunner {
+        self.responses
+            .push((String::from(pattern), Ok(String::from(stdout))));
+        self
+    }
+
+    pub fn fail(mut self, pattern: &str, stderr: &str) -> FakeRunner {
+        self.responses
+            .push((String::from(pattern), Err(String::from(stderr))));
+        self
+    }
+
+    /// Command lines run so far, prefixed with the environment they added
+    /// like `KUBECONFIG=/path minikube start`.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        env: &[(&str, &str)],
+        _input: Option<&str>,
+        _timeout: Option<Duration>,
+    ) -> Result<String> {
+        let line = cmd::command_line(program, args);
+        let mut call: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        call.push(line.clone());
+        self.calls.borrow_mut().push(call.join(" "));
+
+        match self.responses.iter().find(|(p, _)| line.contains(&p[..])) {
+            Some((_, Ok(stdout))) => Ok(stdout.clone()),
+            Some((_, Err(stderr))) => Err(CommandError {
+                command: line,
+                code: Some(1),
+                stderr: stderr.clone(),
+            }
+            .into()),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn run_attached(&self, program: &str, args: &[&str], env: &[(&str, &str)]) -> Result<()> {
+        self.run(program, args, env, None, None).map(|_| ())
+    }
+}
+
+/// A tiny HTTP server answering every request with `handler`, which gets
+/// the method, path and body, and returns the status code and body.
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    pub fn start<F>(handler: F) -> MockServer
+    where
+        F: Fn(&str, &str, &str) -> (u16, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or("").to_string();
+                let path = parts.next().unwrap_or("").to_string();
+
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    let lower = header.to_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                seen.lock().unwrap().push(format!("{} {}", method, path));
+                let (status, body) = handler(&method, &path, &String::from_utf8_lossy(&body));
+
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        MockServer { url, requests }
+    }
+
+    /// Requests received so far, like `GET /v2/kubernetes/clusters`.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
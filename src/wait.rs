@@ -0,0 +1,189 @@
+// Waiting for clusters to become ready, polling with an exponential backoff.
+
+use anyhow::{anyhow, Result};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+
+/// Outcome of a single readiness check.
+pub enum Progress {
+    Done,
+    /// Not ready yet, with a description of what is missing.
+    Pending(String),
+}
+
+/// Parses durations like `90`, `90s`, `5m`, `1h` or `1m30s`. A number
+/// without unit is in seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || {
+        anyhow!(
+            "Invalid duration {}, expected something like 90s, 5m or 1h",
+            duration
+        )
+    };
+
+    if let Ok(secs) = duration.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    if duration.is_empty() {
+        return Err(invalid());
+    }
+    let re = Regex::new(r"^(?:(\d+)h)?(?:(\d+)m)?(?:(\d+)s)?$").unwrap();
+    let cap = re.captures(duration).ok_or_else(invalid)?;
+
+    let part = |i: usize, unit: u64| -> u64 {
+        cap.get(i)
+            .and_then(|p| p.as_str().parse::<u64>().ok())
+            .unwrap_or(0)
+            * unit
+    };
+
+    Ok(Duration::from_secs(
+        part(1, 60 * 60) + part(2, 60) + part(3, 1),
+    ))
+}
+
+/// Formats a duration like `1m30s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 60, secs % 60) {
+        (0, s) => format!("{}s", s),
+        (m, 0) => format!("{}m", m),
+        (m, s) => format!("{}m{}s", m, s),
+    }
+}
+
+/// Delays between checks, doubling from `initial` up to `max`.
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { next: initial, max }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let current = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+
+        Some(current)
+    }
+}
+
+/// Calls `check` until it reports `Done`, logging its progress whenever it
+/// changes. Fails with the last progress message once `timeout` has passed.
+pub fn until<F>(what: &str, timeout: Duration, mut check: F) -> Result<()>
+where
+    F: FnMut() -> Result<Progress>,
+{
+    let start = Instant::now();
+    let mut last = String::new();
+
+    for delay in Backoff::new(Duration::from_secs(2), Duration::from_secs(30)) {
+        match check()? {
+            Progress::Done => return Ok(()),
+            Progress::Pending(message) => {
+                if message != last {
+                    log::info!(
+                        "Waiting for {} ({}): {}",
+                        what,
+                        format_duration(start.elapsed()),
+                        message
+                    );
+                    last = message;
+                }
+            }
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            break;
+        }
+        thread::sleep(std::cmp::min(delay, timeout - elapsed));
+    }
+
+    Err(anyhow!(
+        "Timed out after {} waiting for {}: {}",
+        format_duration(timeout),
+        what,
+        last
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wait::{self, Backoff, Progress};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(wait::parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            wait::parse_duration("90s").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            wait::parse_duration("5m").unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            wait::parse_duration("1h").unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            wait::parse_duration("1m30s").unwrap(),
+            Duration::from_secs(90)
+        );
+
+        assert!(wait::parse_duration("").is_err());
+        assert!(wait::parse_duration("5 minutes").is_err());
+        assert!(wait::parse_duration("30s5m").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(wait::format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(wait::format_duration(Duration::from_secs(120)), "2m");
+        assert_eq!(wait::format_duration(Duration::from_secs(90)), "1m30s");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let delays: Vec<u64> = Backoff::new(Duration::from_secs(2), Duration::from_secs(10))
+            .take(5)
+            .map(|d| d.as_secs())
+            .collect();
+
+        assert_eq!(delays, vec![2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn test_until() {
+        let mut checks = 0;
+        wait::until("nothing", Duration::from_secs(1), || {
+            checks += 1;
+            Ok(Progress::Done)
+        })
+        .unwrap();
+        assert_eq!(checks, 1);
+
+        let err = wait::until("the cluster", Duration::from_secs(0), || {
+            Ok(Progress::Pending(String::from("1/3 nodes running")))
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 0s waiting for the cluster: 1/3 nodes running"
+        );
+    }
+}